@@ -0,0 +1,28 @@
+use clap::ValueEnum;
+use subxt::config::{Config, Hasher, substrate::SubstrateHeader};
+use subxt::utils::H256;
+use subxt::{PolkadotConfig, SubstrateConfig};
+
+/// A subxt config that the indexer can be used with
+///
+/// Block hashes must be `H256`, and headers must be standard Substrate headers with `u32` block numbers. Signed extensions, hashers and account types can be customised.
+pub trait IndexerConfig:
+    Config<Hasher: Hasher<Output = H256>, Header = SubstrateHeader<u32, <Self as Config>::Hasher>>
+{
+    /// Are extrinsics signed with a bare 20 or 32 byte account id rather than a `MultiAddress`?
+    const BARE_ADDRESS: bool = false;
+}
+
+impl IndexerConfig for PolkadotConfig {}
+
+impl IndexerConfig for SubstrateConfig {}
+
+/// Ready-made configs that can be chosen on the command line
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChainConfig {
+    /// Polkadot, Kusama and their system chains
+    #[default]
+    Polkadot,
+    /// Generic Substrate chains
+    Substrate,
+}
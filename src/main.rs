@@ -1,3 +1,5 @@
+#![allow(clippy::result_large_err)]
+
 use std::{
     path::PathBuf,
     process::exit,
@@ -5,7 +7,7 @@ use std::{
 };
 
 use clap::{
-    Parser,
+    Parser, ValueEnum,
     builder::{
         Styles,
         styling::{AnsiColor, Effects},
@@ -13,21 +15,25 @@ use clap::{
 };
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use color_eyre::eyre::Result;
-use futures::StreamExt;
+use futures::{StreamExt, stream::FuturesUnordered};
 use signal_hook::{consts::TERM_SIGNALS, flag};
 use signal_hook_tokio::Signals;
 use sled::Tree;
-use subxt::{
-    OnlineClient, PolkadotConfig, backend::rpc::RpcClient, ext::subxt_rpcs::LegacyRpcMethods,
-};
-use tokio::{join, spawn, sync::watch};
+use subxt::{PolkadotConfig, SubstrateConfig};
+use tokio::{join, spawn, sync::watch, task::JoinHandle};
+use tracing::{Instrument, info_span};
 use tracing_log::{
     AsTrace,
     log::{error, info},
 };
 
+use crate::config::ChainConfig;
+use crate::shared::{IndexError, IndexerStatus};
 use crate::websockets::websockets_listen;
 
+pub mod config;
+pub mod migrations;
+pub mod remark;
 pub mod shared;
 pub mod substrate;
 pub mod websockets;
@@ -50,15 +56,39 @@ pub struct Args {
     /// Database path
     #[arg(short, long)]
     pub db_path: Option<String>,
-    /// URL of Substrate node to connect to
-    #[arg(short, long)]
-    pub url: Option<String>,
-    /// Maximum number of concurrent requests to the chain
+    /// Chain to index as NAME[:CONFIG]=URL[,URL...][,OPTION...], can be repeated [default: kusama with --url]
+    #[arg(short, long, value_parser = parse_chain)]
+    pub chain: Vec<ChainArg>,
+    /// URLs of Substrate nodes for the default chain, tried in turn when the connection fails. Not used with --chain
+    #[arg(short, long, value_delimiter = ',')]
+    pub url: Vec<String>,
+    /// Chain to move the data of a database from before multiple chains were indexed to
+    #[arg(long, value_parser = parse_chain_name)]
+    pub legacy_chain: Option<String>,
+    /// Config for chains that don't specify one
+    #[arg(long, value_enum, default_value_t = ChainConfig::Polkadot)]
+    pub config: ChainConfig,
+    /// Bind the database to the chain of the node even if it was created for a different chain, per chain with rebind-chain
+    #[arg(long, default_value_t = false)]
+    pub rebind_chain: bool,
+    /// Retry blocks that failed too many times in earlier runs
+    #[arg(long, default_value_t = false)]
+    pub retry_poisoned: bool,
+    /// Maximum number of concurrent requests to each chain, per chain with queue-depth=N
     #[arg(long, default_value_t = 1)]
     pub queue_depth: u8,
-    /// Load feathers from blocks before they are finalized
+    /// Load feathers from blocks before they are finalized, per chain with best
     #[arg(short, long, default_value_t = false)]
     pub best: bool,
+    /// Lowest block to index, per chain with from-block=N [default: 0]
+    #[arg(long)]
+    pub from_block: Option<u32>,
+    /// Highest block to start batch indexing from, per chain with to-block=N [default: the current head]
+    #[arg(long)]
+    pub to_block: Option<u32>,
+    /// Number of blocks after the first part of a multi-part feather in which the other parts must be posted, per chain with part-window=N
+    #[arg(long, default_value_t = 600)]
+    pub part_window: u32,
     /// Port to open for WebSocket queries
     #[arg(short, long, default_value_t = 8172)]
     pub port: u16,
@@ -66,24 +96,197 @@ pub struct Args {
     verbose: Verbosity<InfoLevel>,
 }
 
+/// A chain to index, the URLs of its nodes and the settings that differ from the defaults
+#[derive(Debug, Clone)]
+pub struct ChainArg {
+    pub name: String,
+    pub config: Option<ChainConfig>,
+    pub urls: Vec<String>,
+    pub rebind_chain: bool,
+    pub best: bool,
+    pub queue_depth: Option<u8>,
+    pub from_block: Option<u32>,
+    pub to_block: Option<u32>,
+    pub part_window: Option<u32>,
+}
+
+impl ChainArg {
+    /// Settings for indexing the chain, taking the defaults from the command line.
+    fn settings(&self, args: &Args) -> ChainSettings {
+        ChainSettings {
+            rebind_chain: self.rebind_chain || args.rebind_chain,
+            best: self.best || args.best,
+            queue_depth: self.queue_depth.unwrap_or(args.queue_depth),
+            from_block: self.from_block.or(args.from_block).unwrap_or(0),
+            to_block: self.to_block.or(args.to_block),
+            part_window: self.part_window.unwrap_or(args.part_window),
+        }
+    }
+}
+
+/// Settings for indexing a chain
+#[derive(Debug, Clone, Copy)]
+pub struct ChainSettings {
+    /// Bind the database to the chain of the node even if it was created for a different chain
+    pub rebind_chain: bool,
+    /// Load feathers from blocks before they are finalized
+    pub best: bool,
+    /// Maximum number of concurrent requests to the chain
+    pub queue_depth: u8,
+    /// Lowest block to index
+    pub from_block: u32,
+    /// Highest block to start batch indexing from, or the current head
+    pub to_block: Option<u32>,
+    /// Number of blocks after the first part of a multi-part feather in which the other parts must be posted
+    pub part_window: u32,
+}
+
+/// Parse a chain as NAME[:CONFIG]=URL[,URL...][,OPTION...]. Entries with `://` are URLs, the others are options.
+fn parse_chain(arg: &str) -> Result<ChainArg, String> {
+    let Some((name, entries)) = arg.split_once('=') else {
+        return Err("expected NAME[:CONFIG]=URL[,URL...][,OPTION...]".into());
+    };
+    let (name, config) = match name.split_once(':') {
+        Some((name, config)) => (name, Some(ChainConfig::from_str(config, true)?)),
+        None => (name, None),
+    };
+    let mut chain = ChainArg {
+        name: parse_chain_name(name)?,
+        config,
+        urls: vec![],
+        rebind_chain: false,
+        best: false,
+        queue_depth: None,
+        from_block: None,
+        to_block: None,
+        part_window: None,
+    };
+    for entry in entries.split(',').filter(|entry| !entry.is_empty()) {
+        if entry.contains("://") {
+            chain.urls.push(entry.into());
+            continue;
+        }
+        let (option, value) = match entry.split_once('=') {
+            Some((option, value)) => (option, Some(value)),
+            None => (entry, None),
+        };
+        match (option, value) {
+            ("rebind-chain", None) => chain.rebind_chain = true,
+            ("best", None) => chain.best = true,
+            ("queue-depth", _) => chain.queue_depth = Some(parse_option_number(option, value)?),
+            ("from-block", _) => chain.from_block = Some(parse_option_number(option, value)?),
+            ("to-block", _) => chain.to_block = Some(parse_option_number(option, value)?),
+            ("part-window", _) => chain.part_window = Some(parse_option_number(option, value)?),
+            _ => {
+                return Err(format!(
+                    "unknown option for chain {}: {:?}",
+                    chain.name, entry
+                ));
+            }
+        }
+    }
+    if chain.urls.is_empty() {
+        return Err(format!("no URLs for chain {}", chain.name));
+    }
+    Ok(chain)
+}
+
+/// Parse the number of a chain option given as OPTION=N.
+fn parse_option_number<T: std::str::FromStr>(
+    option: &str,
+    value: Option<&str>,
+) -> Result<T, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("expected {}=N", option))
+}
+
+fn parse_chain_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("invalid chain name: {:?}", name));
+    }
+    Ok(name.into())
+}
+
 /// Database trees for the indexer
 #[derive(Clone)]
 pub struct Trees {
     pub root: sled::Db,
+    /// Name of the chain the trees belong to
+    pub chain: String,
+    /// Genesis hash and name of the chain the trees were created for
+    pub metadata: Tree,
     pub span: Tree,
     pub feather: Tree,
+    pub block_hash: Tree,
+    pub extrinsic: Tree,
+    pub timestamp: Tree,
+    pub failed: Tree,
+    /// Remarks with the feather prefix that could not be parsed, and the reason
+    pub rejected: Tree,
+    /// Edits and deletions of feathers, by the key of the revision
+    pub revision: Tree,
+    /// Keys of the revisions of each feather, by the feather they revise
+    pub revision_index: Tree,
+    /// Keys of the replies to each feather, by the feather they reply to
+    pub reply: Tree,
+    /// Parts of multi-part feathers, by the key of the part
+    pub part: Tree,
+    /// Keys of the parts of each set of parts, by the author and id of the set
+    pub part_set: Tree,
+    pub poisoned: Tree,
 }
 
-pub fn open_trees(db_config: sled::Config) -> Result<Trees, sled::Error> {
-    let db = db_config.open()?;
+/// Database trees and indexer progress for a chain
+#[derive(Clone)]
+pub struct IndexedChain {
+    pub trees: Trees,
+    pub status_rx: watch::Receiver<IndexerStatus>,
+    /// Number of blocks in which all the parts of a feather must be posted
+    pub part_window: u32,
+}
+
+pub fn open_trees(db: &sled::Db, chain: &str) -> Result<Trees, sled::Error> {
+    let open_tree = |name: &str| db.open_tree(format!("{}/{}", chain, name));
     let trees = Trees {
         root: db.clone(),
-        span: db.open_tree(b"span")?,
-        feather: db.open_tree(b"feather")?,
+        chain: chain.into(),
+        metadata: open_tree("metadata")?,
+        span: open_tree("span")?,
+        feather: open_tree("feather")?,
+        block_hash: open_tree("block_hash")?,
+        extrinsic: open_tree("extrinsic")?,
+        timestamp: open_tree("timestamp")?,
+        failed: open_tree("failed")?,
+        rejected: open_tree("rejected")?,
+        revision: open_tree("revision")?,
+        revision_index: open_tree("revision_index")?,
+        reply: open_tree("reply")?,
+        part: open_tree("part")?,
+        part_set: open_tree("part_set")?,
+        poisoned: open_tree("poisoned")?,
     };
     Ok(trees)
 }
 
+/// Wait for an indexer to fail or panic.
+async fn indexer_failed(tasks: &mut FuturesUnordered<JoinHandle<Result<(), IndexError>>>) {
+    while let Some(result) = tasks.next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                error!("Indexer failed: {}", err);
+                return;
+            }
+            Err(err) => {
+                error!("Indexer panicked: {}", err);
+                return;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
@@ -92,7 +295,7 @@ async fn main() -> Result<()> {
     let log_level = args.verbose.log_level_filter().as_trace();
     tracing_subscriber::fmt().with_max_level(log_level).init();
     // Open database.
-    let db_path = match args.db_path {
+    let db_path = match args.db_path.clone() {
         Some(db_path) => PathBuf::from(db_path),
         None => match home::home_dir() {
             Some(mut db_path) => {
@@ -106,36 +309,71 @@ async fn main() -> Result<()> {
             }
         },
     };
+    // Determine the chains to index and the urls of Substrate nodes to connect to.
+    if !args.chain.is_empty() && !args.url.is_empty() {
+        error!("--url is for the default chain. Give the URLs of each chain in --chain instead.");
+        exit(1);
+    }
+    // Block bounds and rebinding are specific to a chain.
+    if args.chain.len() > 1
+        && (args.from_block.is_some() || args.to_block.is_some() || args.rebind_chain)
+    {
+        error!(
+            "--from-block, --to-block and --rebind-chain can't be used with several chains. Give them as options of each --chain instead."
+        );
+        exit(1);
+    }
+    let chains = match args.chain.is_empty() {
+        false => args.chain.clone(),
+        true => vec![ChainArg {
+            name: "kusama".into(),
+            config: None,
+            urls: match args.url.is_empty() {
+                false => args.url.clone(),
+                true => vec!["wss://kusama-rpc.polkadot.io:443".into()],
+            },
+            rebind_chain: false,
+            best: false,
+            queue_depth: None,
+            from_block: None,
+            to_block: None,
+            part_window: None,
+        }],
+    };
+    for (i, chain) in chains.iter().enumerate() {
+        if chains[..i].iter().any(|other| other.name == chain.name) {
+            error!("Chain {} configured more than once.", chain.name);
+            exit(1);
+        }
+    }
     info!("Database path: {}", db_path.display());
-    let db_config = sled::Config::new().path(db_path);
-    let trees = match open_trees(db_config) {
-        Ok(trees) => trees,
+    let db = match sled::Config::new().path(db_path).open() {
+        Ok(db) => db,
         Err(_) => {
             error!("Failed to open database.");
             exit(1);
         }
     };
-    // Determine url of Substrate node to connect to.
-    let url = match args.url {
-        Some(url) => url,
-        None => "wss://kusama-rpc.polkadot.io:443".into(),
-    };
-    info!("Connecting to: {}", url);
-    let rpc_client = match RpcClient::from_url(&url).await {
-        Ok(rpc_client) => rpc_client,
-        Err(err) => {
-            error!("Failed to connect: {}", err);
-            exit(1);
-        }
-    };
-    let api = match OnlineClient::<PolkadotConfig>::from_rpc_client(rpc_client.clone()).await {
-        Ok(api) => api,
-        Err(err) => {
-            error!("Failed to connect: {}", err);
-            exit(1);
+    let mut chain_trees = vec![];
+    let result = migrations::migrate(&db, args.legacy_chain.as_deref()).and_then(|()| {
+        for chain in &chains {
+            let trees = open_trees(&db, &chain.name)?;
+            if args.retry_poisoned {
+                info!(
+                    "Retrying {} poisoned blocks of chain {}.",
+                    trees.poisoned.len(),
+                    chain.name
+                );
+                trees.poisoned.clear()?;
+            }
+            chain_trees.push(trees);
         }
-    };
-    let rpc = LegacyRpcMethods::<PolkadotConfig>::new(rpc_client);
+        Ok(())
+    });
+    if result.is_err() {
+        error!("Failed to open database.");
+        exit(1);
+    }
     // https://docs.rs/signal-hook/0.3.17/signal_hook/#a-complex-signal-handling-with-a-background-thread
     // Make sure double CTRL+C and similar kills.
     let term_now = Arc::new(AtomicBool::new(false));
@@ -150,25 +388,95 @@ async fn main() -> Result<()> {
     }
     // Create a watch channel to exit the program.
     let (exit_tx, exit_rx) = watch::channel(false);
-    // Start indexer thread.
-    let substrate_index = spawn(substrate::substrate_index(
-        trees.clone(),
-        api.clone(),
-        rpc.clone(),
-        args.best,
-        args.queue_depth,
-        exit_rx.clone(),
-    ));
+    let mut indexed_chains = vec![];
+    let mut substrate_tasks = FuturesUnordered::new();
+    for (chain, trees) in chains.into_iter().zip(chain_trees) {
+        // Create a watch channel to report the progress of the indexer.
+        let (status_tx, status_rx) = watch::channel(IndexerStatus::default());
+        // Start indexer thread.
+        let span = info_span!("chain", name = chain.name);
+        let settings = chain.settings(&args);
+        let task = match chain.config.unwrap_or(args.config) {
+            ChainConfig::Polkadot => spawn(
+                substrate::substrate_index::<PolkadotConfig>(
+                    trees.clone(),
+                    chain.urls,
+                    settings,
+                    status_tx,
+                    exit_rx.clone(),
+                )
+                .instrument(span),
+            ),
+            ChainConfig::Substrate => spawn(
+                substrate::substrate_index::<SubstrateConfig>(
+                    trees.clone(),
+                    chain.urls,
+                    settings,
+                    status_tx,
+                    exit_rx.clone(),
+                )
+                .instrument(span),
+            ),
+        };
+        substrate_tasks.push(task);
+        indexed_chains.push(IndexedChain {
+            trees,
+            status_rx,
+            part_window: settings.part_window,
+        });
+    }
     // Spawn websockets task.
-    let websockets_task = spawn(websockets_listen(trees.clone(), args.port, exit_rx));
-    // Wait for signal.
+    let websockets_task = spawn(websockets_listen(indexed_chains, args.port, exit_rx));
+    // Wait for signal, or for an indexer to fail.
     let mut signals = Signals::new(TERM_SIGNALS).unwrap();
-    signals.next().await;
+    let exit_code = tokio::select! {
+        _ = signals.next() => 0,
+        _ = indexer_failed(&mut substrate_tasks) => 1,
+    };
     info!("Exiting.");
     let _ = exit_tx.send(true);
     // Wait to exit.
-    let _result = join!(substrate_index, websockets_task);
+    let _result = join!(substrate_tasks.collect::<Vec<_>>(), websockets_task);
     // Close db.
     // let _ = close_trees::<R>(trees);
-    exit(0);
+    exit(exit_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_with_options() {
+        let chain =
+            parse_chain("polkadot:substrate=wss://a,from-block=5,wss://b,best,part-window=10")
+                .unwrap();
+        assert_eq!(chain.name, "polkadot");
+        assert_eq!(chain.config, Some(ChainConfig::Substrate));
+        assert_eq!(chain.urls, ["wss://a", "wss://b"]);
+        assert!(chain.best && !chain.rebind_chain);
+        assert_eq!(chain.from_block, Some(5));
+        assert_eq!(chain.to_block, None);
+        assert_eq!(chain.part_window, Some(10));
+    }
+
+    #[test]
+    fn chain_settings_default_to_flags() {
+        let args = Args::parse_from(["feather-index", "--queue-depth", "4", "--from-block", "7"]);
+        let chain = parse_chain("kusama=wss://a,queue-depth=2").unwrap();
+        let settings = chain.settings(&args);
+        assert_eq!(settings.queue_depth, 2);
+        assert_eq!(settings.from_block, 7);
+        assert_eq!(settings.part_window, 600);
+    }
+
+    #[test]
+    fn invalid_chains() {
+        assert!(parse_chain("kusama").is_err());
+        assert!(parse_chain("kusama=best").is_err());
+        assert!(parse_chain("kusama=wss://a,unknown").is_err());
+        assert!(parse_chain("kusama=wss://a,from-block").is_err());
+        assert!(parse_chain("kusama=wss://a,from-block=x").is_err());
+        assert!(parse_chain("ku/sama=wss://a").is_err());
+    }
 }
@@ -0,0 +1,361 @@
+use sled::Tree;
+use tokio::time::Instant;
+use tracing_log::log::{error, info};
+
+use crate::shared::{FeatherDbValue, IndexError};
+
+/// Version of the database layout written by this version of the indexer
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// An upgrade of the database layout from the previous schema version
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: fn(&sled::Db, Option<&str>) -> Result<(), IndexError>,
+}
+
+/// All migrations in order of schema version
+const MIGRATIONS: [Migration; 4] = [
+    Migration {
+        version: 1,
+        description: "add sub-indices to feather keys",
+        run: upgrade_feather_keys,
+    },
+    Migration {
+        version: 2,
+        description: "add remark encodings to feather values",
+        run: upgrade_feather_values,
+    },
+    Migration {
+        version: 3,
+        description: "move trees to the legacy chain",
+        run: upgrade_legacy_trees,
+    },
+    Migration {
+        version: 4,
+        description: "add protocol versions to feather values",
+        run: upgrade_feather_versions,
+    },
+];
+
+/// Names of the trees written before multiple chains were indexed
+const LEGACY_TREE_NAMES: [&str; 7] = [
+    "span",
+    "feather",
+    "block_hash",
+    "extrinsic",
+    "timestamp",
+    "failed",
+    "poisoned",
+];
+
+/// Upgrade the database to the current schema version, refusing databases written by newer versions.
+///
+/// Databases without a schema version were written before versioning. All the migrations can detect data that has already been upgraded, so they are all run.
+///
+/// Data from before multiple chains were indexed is only moved to `legacy_chain`, which the operator must name.
+pub fn migrate(db: &sled::Db, legacy_chain: Option<&str>) -> Result<(), IndexError> {
+    let schema = db.open_tree(b"schema")?;
+    let version = match schema.get(b"version")? {
+        Some(version) => u32::from_be_bytes(
+            version
+                .as_ref()
+                .try_into()
+                .map_err(|_| IndexError::ParseError)?,
+        ),
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        error!(
+            "Database schema version {} is newer than supported version {}.",
+            version, SCHEMA_VERSION
+        );
+        return Err(IndexError::UnsupportedSchema(version));
+    }
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+    {
+        info!(
+            "Migrating database to schema version {}: {}.",
+            migration.version, migration.description
+        );
+        let start = Instant::now();
+        (migration.run)(db, legacy_chain)?;
+        schema.insert(b"version", &migration.version.to_be_bytes())?;
+        db.flush()?;
+        info!(
+            "Migrated database to schema version {} in {:.1?}.",
+            migration.version,
+            start.elapsed()
+        );
+    }
+    Ok(())
+}
+
+/// Existing trees with a name, either from before multiple chains were indexed or for any chain.
+fn existing_trees(db: &sled::Db, name: &str) -> Result<Vec<Tree>, sled::Error> {
+    let suffix = format!("/{}", name);
+    let mut trees = vec![];
+    for tree_name in db.tree_names() {
+        if tree_name == name.as_bytes() || tree_name.ends_with(suffix.as_bytes()) {
+            trees.push(db.open_tree(tree_name)?);
+        }
+    }
+    Ok(trees)
+}
+
+/// Add a sub-index to feather keys that were written before feathers nested in batches were indexed.
+fn upgrade_feather_keys(db: &sled::Db, _legacy_chain: Option<&str>) -> Result<(), IndexError> {
+    for feather in existing_trees(db, "feather")? {
+        let mut batch = sled::Batch::default();
+        let mut count: u32 = 0;
+        for (key, value) in feather.iter().flatten() {
+            if key.len() == 38 {
+                let mut new_key = key[..6].to_vec();
+                new_key.extend_from_slice(&[0, 0]);
+                new_key.extend_from_slice(&key[6..]);
+                batch.insert(new_key, value);
+                batch.remove(key);
+                count += 1;
+            }
+        }
+        if count != 0 {
+            info!("Upgrading {} feather keys.", count);
+            feather.apply_batch(batch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite feather values that were stored before remarks were decoded as UTF-8.
+///
+/// Each byte of the remark was stored as the char with the same value, so the original bytes can be recovered. Values in the old format always start with `F` from the `FEATHER` prefix.
+fn upgrade_feather_values(db: &sled::Db, _legacy_chain: Option<&str>) -> Result<(), IndexError> {
+    for tree in existing_trees(db, "feather")?
+        .into_iter()
+        .chain(existing_trees(db, "failed")?)
+    {
+        let mut batch = sled::Batch::default();
+        let mut count: u32 = 0;
+        for (key, value) in tree.iter().flatten() {
+            if value.first() != Some(&b'F') {
+                continue;
+            }
+            let Ok(remark) = std::str::from_utf8(&value) else {
+                continue;
+            };
+            let Ok(remark) = remark
+                .chars()
+                .map(u8::try_from)
+                .collect::<Result<Vec<u8>, _>>()
+            else {
+                continue;
+            };
+            batch.insert(key, FeatherDbValue::encode(1, &remark));
+            count += 1;
+        }
+        if count != 0 {
+            info!("Upgrading {} feather values.", count);
+            tree.apply_batch(batch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Move the trees of a database written before multiple chains were indexed to the legacy chain.
+///
+/// Legacy databases don't record which chain they were written for, so the operator must name it.
+fn upgrade_legacy_trees(db: &sled::Db, legacy_chain: Option<&str>) -> Result<(), IndexError> {
+    let tree_names = db.tree_names();
+    let legacy_names: Vec<&str> = LEGACY_TREE_NAMES
+        .into_iter()
+        .filter(|name| {
+            tree_names
+                .iter()
+                .any(|tree_name| tree_name == name.as_bytes())
+        })
+        .collect();
+    if legacy_names.is_empty() {
+        return Ok(());
+    }
+    let Some(legacy_chain) = legacy_chain else {
+        error!(
+            "Database has data from before multiple chains were indexed. Pass --legacy-chain to name the chain it was written for."
+        );
+        return Err(IndexError::UnnamedLegacyChain);
+    };
+    for name in legacy_names {
+        let legacy = db.open_tree(name)?;
+        if !legacy.is_empty() {
+            info!(
+                "Moving {} {} entries to chain {}.",
+                legacy.len(),
+                name,
+                legacy_chain
+            );
+            let mut batch = sled::Batch::default();
+            for (key, value) in legacy.iter().flatten() {
+                batch.insert(key, value);
+            }
+            db.open_tree(format!("{}/{}", legacy_chain, name))?
+                .apply_batch(batch)?;
+        }
+        db.drop_tree(name)?;
+    }
+    Ok(())
+}
+
+/// Add the protocol version to feather values that were stored before versions were recognised. These feathers were all written with version 1.
+///
+/// Values without a version have the `F` from the `FEATHER` prefix straight after the encoding.
+fn upgrade_feather_versions(db: &sled::Db, _legacy_chain: Option<&str>) -> Result<(), IndexError> {
+    for tree in existing_trees(db, "feather")?
+        .into_iter()
+        .chain(existing_trees(db, "failed")?)
+    {
+        let mut batch = sled::Batch::default();
+        let mut count: u32 = 0;
+        for (key, value) in tree.iter().flatten() {
+            if value.get(1) != Some(&b'F') {
+                continue;
+            }
+            let mut new_value = vec![value[0], 1];
+            new_value.extend_from_slice(&value[1..]);
+            batch.insert(key, new_value);
+            count += 1;
+        }
+        if count != 0 {
+            info!("Upgrading {} feather values.", count);
+            tree.apply_batch(batch)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remarks of the baseline feathers, including bytes that are not ASCII
+    const REMARKS: [&[u8]; 2] = [b"FEATHER::x::y::caf\xc3\xa9", b"FEATHER::x::y::caf\xe9"];
+
+    /// Key of a baseline feather: block number, index and account id.
+    fn baseline_key(block_number: u32) -> Vec<u8> {
+        let mut key = block_number.to_be_bytes().to_vec();
+        key.extend_from_slice(&2u16.to_be_bytes());
+        key.extend_from_slice(&[7; 32]);
+        key
+    }
+
+    /// A database in the layout written before schema versioning, with each remark byte stored as a char.
+    fn baseline_db() -> sled::Db {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let feather = db.open_tree("feather").unwrap();
+        for (i, remark) in REMARKS.iter().enumerate() {
+            let value: String = remark.iter().copied().map(char::from).collect();
+            feather
+                .insert(baseline_key(100 + i as u32), value.as_bytes())
+                .unwrap();
+        }
+        db.open_tree("span")
+            .unwrap()
+            .insert(101u32.to_be_bytes(), &90u32.to_be_bytes())
+            .unwrap();
+        db.open_tree("block_hash")
+            .unwrap()
+            .insert(100u32.to_be_bytes(), &[1; 32])
+            .unwrap();
+        db
+    }
+
+    /// Every entry of every tree, with the name of the tree.
+    fn contents(db: &sled::Db) -> Vec<[Vec<u8>; 3]> {
+        let mut entries = vec![];
+        for name in db.tree_names() {
+            for (key, value) in db.open_tree(&name).unwrap().iter().flatten() {
+                entries.push([name.to_vec(), key.to_vec(), value.to_vec()]);
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn migrate_baseline() {
+        let db = baseline_db();
+        migrate(&db, Some("kusama")).unwrap();
+        let tree_names = db.tree_names();
+        for name in LEGACY_TREE_NAMES {
+            assert!(
+                !tree_names
+                    .iter()
+                    .any(|tree_name| tree_name == name.as_bytes())
+            );
+        }
+        let feather = db.open_tree("kusama/feather").unwrap();
+        assert_eq!(feather.len(), REMARKS.len());
+        for (i, remark) in REMARKS.iter().enumerate() {
+            let key = baseline_key(100 + i as u32);
+            let key = [&key[..6], &[0, 0], &key[6..]].concat();
+            assert_eq!(
+                feather.get(key).unwrap().unwrap().as_ref(),
+                FeatherDbValue::encode(1, remark)
+            );
+        }
+        let span = db.open_tree("kusama/span").unwrap();
+        assert_eq!(
+            span.get(101u32.to_be_bytes()).unwrap().unwrap().as_ref(),
+            90u32.to_be_bytes()
+        );
+        let block_hash = db.open_tree("kusama/block_hash").unwrap();
+        assert_eq!(block_hash.len(), 1);
+        let schema = db.open_tree("schema").unwrap();
+        assert_eq!(
+            schema.get(b"version").unwrap().unwrap().as_ref(),
+            SCHEMA_VERSION.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn migrate_again_is_noop() {
+        let db = baseline_db();
+        migrate(&db, Some("kusama")).unwrap();
+        let migrated = contents(&db);
+        migrate(&db, Some("kusama")).unwrap();
+        assert_eq!(contents(&db), migrated);
+        // Without a schema version all the migrations run again.
+        db.open_tree("schema").unwrap().remove(b"version").unwrap();
+        migrate(&db, Some("kusama")).unwrap();
+        assert_eq!(contents(&db), migrated);
+    }
+
+    #[test]
+    fn refuse_newer_schema() {
+        let db = baseline_db();
+        db.open_tree("schema")
+            .unwrap()
+            .insert(b"version", &(SCHEMA_VERSION + 1).to_be_bytes())
+            .unwrap();
+        let before = contents(&db);
+        assert!(matches!(
+            migrate(&db, Some("kusama")),
+            Err(IndexError::UnsupportedSchema(version)) if version == SCHEMA_VERSION + 1
+        ));
+        assert_eq!(contents(&db), before);
+    }
+
+    #[test]
+    fn refuse_unnamed_legacy_chain() {
+        let db = baseline_db();
+        assert!(matches!(
+            migrate(&db, None),
+            Err(IndexError::UnnamedLegacyChain)
+        ));
+        assert!(
+            db.tree_names()
+                .iter()
+                .any(|name| name == b"feather".as_slice())
+        );
+    }
+}
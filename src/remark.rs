@@ -0,0 +1,478 @@
+/// Prefix that marks a remark as a feather, optionally followed by `:` and the protocol version
+const FEATHER_PREFIX: &[u8] = b"FEATHER";
+
+/// Maximum length of the id of a set of parts
+const MAX_PART_ID_LEN: usize = 255;
+
+/// Reasons a remark with the feather prefix can be rejected
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum RemarkError {
+    #[error("missing genre")]
+    MissingGenre = 0,
+    #[error("missing title")]
+    MissingTitle = 1,
+    #[error("missing content")]
+    MissingContent = 2,
+    #[error("empty genre")]
+    EmptyGenre = 3,
+    #[error("genre or title is not valid UTF-8")]
+    InvalidUtf8 = 4,
+    #[error("unescaped separator in content")]
+    UnescapedSeparator = 5,
+    #[error("unsupported protocol version")]
+    UnsupportedVersion = 6,
+    #[error("missing operation")]
+    MissingOperation = 7,
+    #[error("unknown operation")]
+    UnknownOperation = 8,
+    #[error("missing block number or index of the feather")]
+    MissingReference = 9,
+    #[error("invalid block number or index of the feather")]
+    InvalidReference = 10,
+    #[error("reply to a feather that is not earlier")]
+    ReplyToLater = 11,
+    #[error("invalid part")]
+    InvalidPart = 12,
+    #[error("parts don't form a feather")]
+    InvalidParts = 13,
+    #[error("edit or deletion of a feather that is not earlier")]
+    RevisionOfLater = 14,
+}
+
+impl RemarkError {
+    /// Decode a stored rejection reason.
+    pub fn from_u8(reason: u8) -> Option<RemarkError> {
+        Some(match reason {
+            0 => RemarkError::MissingGenre,
+            1 => RemarkError::MissingTitle,
+            2 => RemarkError::MissingContent,
+            3 => RemarkError::EmptyGenre,
+            4 => RemarkError::InvalidUtf8,
+            5 => RemarkError::UnescapedSeparator,
+            6 => RemarkError::UnsupportedVersion,
+            7 => RemarkError::MissingOperation,
+            8 => RemarkError::UnknownOperation,
+            9 => RemarkError::MissingReference,
+            10 => RemarkError::InvalidReference,
+            11 => RemarkError::ReplyToLater,
+            12 => RemarkError::InvalidPart,
+            13 => RemarkError::InvalidParts,
+            14 => RemarkError::RevisionOfLater,
+            _ => return None,
+        })
+    }
+}
+
+/// A feather operation parsed from a remark
+///
+/// Fields are separated by `::`. From version 2, a `:` or `\` inside a field can be escaped with `\`, so `\::` is a literal `::`. Other backslashes are kept as they are. Version 1 has no escapes, and its content is everything after the title.
+///
+/// The prefix selects the version of the protocol:
+/// - version 1: `FEATHER::genre::title::content`
+/// - version 2: `FEATHER:2::post::genre::title::content`, `FEATHER:2::reply::block_number::index::genre::title::content`, `FEATHER:2::edit::block_number::index::genre::title::content`, `FEATHER:2::delete::block_number::index` or `FEATHER:2::part::id::part::count::data`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatherRemark {
+    /// Version of the protocol the remark was written with
+    pub version: u8,
+    pub op: FeatherOp,
+}
+
+/// Operation of a feather remark
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeatherOp {
+    /// Post a new feather
+    Post(FeatherPost),
+    /// Post a new feather in reply to an existing feather
+    Reply(FeatherRef, FeatherPost),
+    /// Replace an existing feather of the same author
+    Edit(FeatherRef, FeatherPost),
+    /// Retract an existing feather of the same author
+    Delete(FeatherRef),
+    /// Part of a remark that is too long to post in one
+    Part(FeatherPart),
+}
+
+/// Genre, title and content of a feather
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatherPost {
+    pub genre: String,
+    pub title: String,
+    /// Content with any escapes removed, which may be binary
+    pub content: Vec<u8>,
+}
+
+/// Part of a remark split across several remarks
+///
+/// The data of parts `1` to `count` with the same id from the same author is joined to form the remark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatherPart {
+    pub id: String,
+    pub part: u16,
+    pub count: u16,
+    /// Data with escapes removed
+    pub data: Vec<u8>,
+}
+
+/// Reference to a feather by the extrinsic it was posted in
+///
+/// Replies are to the first feather in the extrinsic, and edits and deletions are of the first feather the author posted in the extrinsic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeatherRef {
+    pub block_number: u32,
+    pub index: u16,
+}
+
+impl FeatherRemark {
+    /// Parse a remark. Returns `None` if the remark is not a feather.
+    pub fn parse(remark: &[u8]) -> Result<Option<FeatherRemark>, RemarkError> {
+        let Some(version) = parse_prefix(split_literal(remark, 2)[0]) else {
+            return Ok(None);
+        };
+        let version = version?;
+        let op = match version {
+            // Version 1 remarks were written without escapes, so the content is taken as it is.
+            1 => FeatherOp::Post(parse_post(
+                &mut split_literal(remark, 4)
+                    .into_iter()
+                    .skip(1)
+                    .map(<[u8]>::to_vec),
+            )?),
+            2 => {
+                let mut fields = split_fields(remark).into_iter().skip(1);
+                let op = parse_v2(&mut fields)?;
+                if fields.next().is_some() {
+                    return Err(RemarkError::UnescapedSeparator);
+                }
+                op
+            }
+            _ => return Err(RemarkError::UnsupportedVersion),
+        };
+        Ok(Some(FeatherRemark { version, op }))
+    }
+
+    /// Genre, title and content the remark posts, if any.
+    pub fn post(&self) -> Option<&FeatherPost> {
+        match &self.op {
+            FeatherOp::Post(post) | FeatherOp::Reply(_, post) | FeatherOp::Edit(_, post) => {
+                Some(post)
+            }
+            FeatherOp::Delete(_) | FeatherOp::Part(_) => None,
+        }
+    }
+}
+
+/// Parse the version from the prefix of a remark, `FEATHER` for version 1 or `FEATHER:<version>`. Returns `None` if the remark is not a feather.
+fn parse_prefix(prefix: &[u8]) -> Option<Result<u8, RemarkError>> {
+    let version = prefix.strip_prefix(FEATHER_PREFIX)?;
+    if version.is_empty() {
+        return Some(Ok(1));
+    }
+    let version = version.strip_prefix(b":")?;
+    Some(parse_number(version).ok_or(RemarkError::UnsupportedVersion))
+}
+
+/// Parse a decimal number from a field.
+fn parse_number<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Parse the fields of a version 2 remark, which start with the operation.
+fn parse_v2(fields: &mut impl Iterator<Item = Vec<u8>>) -> Result<FeatherOp, RemarkError> {
+    Ok(
+        match fields
+            .next()
+            .ok_or(RemarkError::MissingOperation)?
+            .as_slice()
+        {
+            b"post" => FeatherOp::Post(parse_post(fields)?),
+            b"reply" => FeatherOp::Reply(parse_ref(fields)?, parse_post(fields)?),
+            b"edit" => FeatherOp::Edit(parse_ref(fields)?, parse_post(fields)?),
+            b"delete" => FeatherOp::Delete(parse_ref(fields)?),
+            b"part" => FeatherOp::Part(parse_part(fields)?),
+            _ => return Err(RemarkError::UnknownOperation),
+        },
+    )
+}
+
+/// Parse the block number and index of the extrinsic a feather was posted in.
+fn parse_ref(fields: &mut impl Iterator<Item = Vec<u8>>) -> Result<FeatherRef, RemarkError> {
+    let mut number = || {
+        fields
+            .next()
+            .ok_or(RemarkError::MissingReference)
+            .and_then(|field| parse_number(&field).ok_or(RemarkError::InvalidReference))
+    };
+    Ok(FeatherRef {
+        block_number: number()?,
+        index: number()?
+            .try_into()
+            .map_err(|_| RemarkError::InvalidReference)?,
+    })
+}
+
+/// Parse the id, number, count and data of a part.
+fn parse_part(fields: &mut impl Iterator<Item = Vec<u8>>) -> Result<FeatherPart, RemarkError> {
+    let mut field = || fields.next().ok_or(RemarkError::InvalidPart);
+    let id = String::from_utf8(field()?).map_err(|_| RemarkError::InvalidPart)?;
+    let part = parse_number(&field()?).ok_or(RemarkError::InvalidPart)?;
+    let count = parse_number(&field()?).ok_or(RemarkError::InvalidPart)?;
+    let data = field()?;
+    if id.is_empty() || id.len() > MAX_PART_ID_LEN || part == 0 || part > count {
+        return Err(RemarkError::InvalidPart);
+    }
+    Ok(FeatherPart {
+        id,
+        part,
+        count,
+        data,
+    })
+}
+
+/// Parse the genre, title and content of a feather.
+fn parse_post(fields: &mut impl Iterator<Item = Vec<u8>>) -> Result<FeatherPost, RemarkError> {
+    let genre = fields.next().ok_or(RemarkError::MissingGenre)?;
+    let title = fields.next().ok_or(RemarkError::MissingTitle)?;
+    let content = fields.next().ok_or(RemarkError::MissingContent)?;
+    if genre.is_empty() {
+        return Err(RemarkError::EmptyGenre);
+    }
+    Ok(FeatherPost {
+        genre: String::from_utf8(genre).map_err(|_| RemarkError::InvalidUtf8)?,
+        title: String::from_utf8(title).map_err(|_| RemarkError::InvalidUtf8)?,
+        content,
+    })
+}
+
+/// Split a remark on at most `count - 1` separators, without looking for escapes.
+fn split_literal(remark: &[u8], count: usize) -> Vec<&[u8]> {
+    let mut fields = vec![];
+    let mut rest = remark;
+    while fields.len() + 1 < count
+        && let Some(i) = rest.windows(2).position(|pair| pair == b"::")
+    {
+        fields.push(&rest[..i]);
+        rest = &rest[i + 2..];
+    }
+    fields.push(rest);
+    fields
+}
+
+/// Split a remark on unescaped `::` separators and remove the escapes.
+fn split_fields(remark: &[u8]) -> Vec<Vec<u8>> {
+    let mut fields = vec![vec![]];
+    let mut i = 0;
+    while i < remark.len() {
+        let next = remark.get(i + 1);
+        match remark[i] {
+            b'\\' if matches!(next, Some(b':' | b'\\')) => {
+                fields.last_mut().unwrap().push(remark[i + 1]);
+                i += 2;
+            }
+            b':' if next == Some(&b':') => {
+                fields.push(vec![]);
+                i += 2;
+            }
+            byte => {
+                fields.last_mut().unwrap().push(byte);
+                i += 1;
+            }
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(genre: &str, title: &str, content: &[u8]) -> FeatherPost {
+        FeatherPost {
+            genre: genre.into(),
+            title: title.into(),
+            content: content.to_vec(),
+        }
+    }
+
+    fn parse_op(remark: &[u8]) -> Result<FeatherOp, RemarkError> {
+        Ok(FeatherRemark::parse(remark)?.unwrap().op)
+    }
+
+    #[test]
+    fn not_a_feather() {
+        assert_eq!(FeatherRemark::parse(b"hello"), Ok(None));
+        assert_eq!(FeatherRemark::parse(b"FEATHERS::a::b::c"), Ok(None));
+        assert_eq!(FeatherRemark::parse(b""), Ok(None));
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER"),
+            Err(RemarkError::MissingGenre)
+        );
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER::x"),
+            Err(RemarkError::MissingTitle)
+        );
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER::x::y"),
+            Err(RemarkError::MissingContent)
+        );
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER::::y::z"),
+            Err(RemarkError::EmptyGenre)
+        );
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER::\xff::y::z"),
+            Err(RemarkError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn version_1() {
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER::theory::Title::Some content"),
+            Ok(Some(FeatherRemark {
+                version: 1,
+                op: FeatherOp::Post(post("theory", "Title", b"Some content")),
+            }))
+        );
+        assert_eq!(
+            parse_op(b"FEATHER::x::y::"),
+            Ok(FeatherOp::Post(post("x", "y", b"")))
+        );
+    }
+
+    #[test]
+    fn version_1_content_is_literal() {
+        assert_eq!(
+            parse_op(b"FEATHER::x::y::a::b\\::c\\\\d\\e"),
+            Ok(FeatherOp::Post(post("x", "y", b"a::b\\::c\\\\d\\e")))
+        );
+    }
+
+    #[test]
+    fn version_2_escapes() {
+        assert_eq!(
+            parse_op(b"FEATHER:2::post::x\\:y::t\\\\::a\\::b\\c"),
+            Ok(FeatherOp::Post(post("x:y", "t\\", b"a::b\\c")))
+        );
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER:2::post::x::y::a::b"),
+            Err(RemarkError::UnescapedSeparator)
+        );
+    }
+
+    #[test]
+    fn bad_versions() {
+        for remark in [
+            &b"FEATHER:3::post::x::y::z"[..],
+            b"FEATHER:0::x::y::z",
+            b"FEATHER:",
+            b"FEATHER:x::post::x::y::z",
+            b"FEATHER:256::post::x::y::z",
+        ] {
+            assert_eq!(
+                FeatherRemark::parse(remark),
+                Err(RemarkError::UnsupportedVersion)
+            );
+        }
+    }
+
+    #[test]
+    fn version_2_post() {
+        assert_eq!(
+            FeatherRemark::parse(b"FEATHER:2::post::x::y::z"),
+            Ok(Some(FeatherRemark {
+                version: 2,
+                op: FeatherOp::Post(post("x", "y", b"z")),
+            }))
+        );
+    }
+
+    #[test]
+    fn version_2_reply() {
+        assert_eq!(
+            parse_op(b"FEATHER:2::reply::100::3::x::y::z"),
+            Ok(FeatherOp::Reply(
+                FeatherRef {
+                    block_number: 100,
+                    index: 3
+                },
+                post("x", "y", b"z")
+            ))
+        );
+        assert_eq!(
+            parse_op(b"FEATHER:2::reply::100"),
+            Err(RemarkError::MissingReference)
+        );
+        assert_eq!(
+            parse_op(b"FEATHER:2::reply::100::70000::x::y::z"),
+            Err(RemarkError::InvalidReference)
+        );
+    }
+
+    #[test]
+    fn version_2_edit() {
+        assert_eq!(
+            parse_op(b"FEATHER:2::edit::100::3::x::y::z"),
+            Ok(FeatherOp::Edit(
+                FeatherRef {
+                    block_number: 100,
+                    index: 3
+                },
+                post("x", "y", b"z")
+            ))
+        );
+        assert_eq!(
+            parse_op(b"FEATHER:2::edit::a::3::x::y::z"),
+            Err(RemarkError::InvalidReference)
+        );
+    }
+
+    #[test]
+    fn version_2_delete() {
+        assert_eq!(
+            parse_op(b"FEATHER:2::delete::100::3"),
+            Ok(FeatherOp::Delete(FeatherRef {
+                block_number: 100,
+                index: 3
+            }))
+        );
+        assert_eq!(
+            parse_op(b"FEATHER:2::delete::100::3::x"),
+            Err(RemarkError::UnescapedSeparator)
+        );
+    }
+
+    #[test]
+    fn version_2_part() {
+        assert_eq!(
+            parse_op(b"FEATHER:2::part::id::1::2::FEATHER\\:\\:x"),
+            Ok(FeatherOp::Part(FeatherPart {
+                id: "id".into(),
+                part: 1,
+                count: 2,
+                data: b"FEATHER::x".to_vec(),
+            }))
+        );
+        for remark in [
+            &b"FEATHER:2::part::id::0::2::x"[..],
+            b"FEATHER:2::part::id::3::2::x",
+            b"FEATHER:2::part::::1::2::x",
+            b"FEATHER:2::part::id::1::2",
+        ] {
+            assert_eq!(parse_op(remark), Err(RemarkError::InvalidPart));
+        }
+    }
+
+    #[test]
+    fn version_2_bad_operations() {
+        assert_eq!(parse_op(b"FEATHER:2"), Err(RemarkError::MissingOperation));
+        assert_eq!(
+            parse_op(b"FEATHER:2::repost::x::y::z"),
+            Err(RemarkError::UnknownOperation)
+        );
+    }
+}
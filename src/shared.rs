@@ -1,14 +1,23 @@
-use serde::{Deserialize, Serialize};
-use subxt::utils::AccountId32;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::fmt;
+use std::str::FromStr;
+use subxt::error::RpcError;
+use subxt::ext::jsonrpsee::core::ClientError;
+use subxt::ext::subxt_rpcs;
+use subxt::utils::{AccountId32, H256};
 use tokio_tungstenite::tungstenite;
 use zerocopy::*;
 use zerocopy_derive::*;
 
+use crate::remark::RemarkError;
+
 /// Errors this crate can return
 #[derive(thiserror::Error, Debug)]
 pub enum IndexError {
     #[error("database error")]
     Sled(#[from] sled::Error),
+    #[error("database error")]
+    Transaction(#[from] sled::transaction::TransactionError),
     #[error("connection error")]
     Subxt(#[from] subxt::Error),
     #[error("connection error")]
@@ -25,6 +34,37 @@ pub enum IndexError {
     CodecError(#[from] subxt::ext::codec::Error),
     #[error("metadata error")]
     MetadataError(#[from] subxt::error::MetadataTryFromError),
+    #[error("connection error")]
+    Disconnected,
+    #[error("connection error")]
+    SubscriptionEnded,
+    #[error("database belongs to a different chain")]
+    WrongChain,
+    #[error("database was written by a newer version")]
+    UnsupportedSchema(u32),
+    #[error("database has data for an unnamed chain")]
+    UnnamedLegacyChain,
+}
+
+impl IndexError {
+    /// Was the error caused by losing the connection to the node?
+    pub fn is_disconnected(&self) -> bool {
+        let error = match self {
+            IndexError::Disconnected => return true,
+            IndexError::Subxt(subxt::Error::Rpc(RpcError::SubscriptionDropped)) => return true,
+            IndexError::Subxt(subxt::Error::Rpc(RpcError::ClientError(error))) => error,
+            IndexError::RpcError(error) => error,
+            _ => return false,
+        };
+        match error {
+            subxt_rpcs::Error::Client(error) => matches!(
+                error.downcast_ref::<ClientError>(),
+                Some(ClientError::RestartNeeded(_))
+            ),
+            subxt_rpcs::Error::DisconnectedWillReconnect(_) => true,
+            _ => false,
+        }
+    }
 }
 
 /// On-disk format for span value
@@ -35,13 +75,339 @@ pub struct SpanDbValue {
     // pub version: U16<BigEndian>,
 }
 
-/// On-disk format for span value
-#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+/// On-disk format for feather key, followed by the account id of the author
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable, Clone)]
 #[repr(C)]
 pub struct FeatherDbKey {
     pub block_number: U32<BigEndian>,
     pub index: U16<BigEndian>,
-    pub account_id: [u8; 32],
+    pub sub_index: U16<BigEndian>,
+}
+
+/// On-disk format for feather value, followed by the remark
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct FeatherDbValue {
+    pub encoding: u8,
+    pub version: u8,
+}
+
+impl FeatherDbValue {
+    /// Encode a remark for storage.
+    pub fn encode(version: u8, remark: &[u8]) -> Vec<u8> {
+        let mut value = FeatherDbValue {
+            encoding: RemarkEncoding::of(remark) as u8,
+            version,
+        }
+        .as_bytes()
+        .to_vec();
+        value.extend_from_slice(remark);
+        value
+    }
+
+    /// Decode a stored remark.
+    pub fn decode(value: &[u8]) -> Option<(RemarkEncoding, u8, &[u8])> {
+        let (header, remark) = FeatherDbValue::read_from_prefix(value).ok()?;
+        let encoding = RemarkEncoding::from_u8(header.encoding)?;
+        Some((encoding, header.version, remark))
+    }
+}
+
+/// On-disk format for revision value, followed by the remark as a feather value
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct RevisionDbValue {
+    /// Block number of the revised feather
+    pub block_number: U32<BigEndian>,
+    /// Extrinsic index of the revised feather
+    pub index: U16<BigEndian>,
+}
+
+/// On-disk format for revision index key, followed by the account id of the author
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct RevisionIndexDbKey {
+    /// Block number of the revised feather
+    pub block_number: U32<BigEndian>,
+    /// Extrinsic index of the revised feather
+    pub index: U16<BigEndian>,
+    /// Key of the revision
+    pub revision: FeatherDbKey,
+}
+
+/// On-disk format for reply key, followed by the account id of the author of the reply
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct ReplyDbKey {
+    /// Block number of the feather replied to
+    pub block_number: U32<BigEndian>,
+    /// Extrinsic index of the feather replied to
+    pub index: U16<BigEndian>,
+    /// Key of the reply
+    pub reply: FeatherDbKey,
+}
+
+/// On-disk format for part value, followed by the id and the data of the part
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct PartDbValue {
+    pub part: U16<BigEndian>,
+    pub count: U16<BigEndian>,
+    pub id_len: u8,
+}
+
+impl PartDbValue {
+    /// Encode a part for storage.
+    pub fn encode(part: u16, count: u16, id: &str, data: &[u8]) -> Vec<u8> {
+        let mut value = PartDbValue {
+            part: part.into(),
+            count: count.into(),
+            id_len: id.len().try_into().unwrap(),
+        }
+        .as_bytes()
+        .to_vec();
+        value.extend_from_slice(id.as_bytes());
+        value.extend_from_slice(data);
+        value
+    }
+
+    /// Decode a stored part into its header, id and data.
+    pub fn decode(value: &[u8]) -> Option<(PartDbValue, &[u8], &[u8])> {
+        let (header, rest) = PartDbValue::read_from_prefix(value).ok()?;
+        let (id, data) = rest.split_at_checked(header.id_len.into())?;
+        Some((header, id, data))
+    }
+}
+
+/// On-disk format for part set key, preceded by the set prefix of the author and id
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct PartSetDbKey {
+    pub part: U16<BigEndian>,
+    /// Key of the part, without the author
+    pub position: FeatherDbKey,
+}
+
+impl PartSetDbKey {
+    /// Prefix of the keys of the parts of a set: the length of the author, the author, the length of the id and the id.
+    pub fn prefix(author: &[u8], id: &[u8]) -> Vec<u8> {
+        let mut prefix = vec![author.len().try_into().unwrap()];
+        prefix.extend_from_slice(author);
+        prefix.push(id.len().try_into().unwrap());
+        prefix.extend_from_slice(id);
+        prefix
+    }
+
+    /// Decode a stored key into its author, id and part.
+    pub fn decode(key: &[u8]) -> Option<(&[u8], &[u8], PartSetDbKey)> {
+        let (author_len, rest) = key.split_first()?;
+        let (author, rest) = rest.split_at_checked((*author_len).into())?;
+        let (id_len, rest) = rest.split_first()?;
+        let (id, rest) = rest.split_at_checked((*id_len).into())?;
+        let part = PartSetDbKey::read_from_bytes(rest).ok()?;
+        Some((author, id, part))
+    }
+}
+
+/// Parts of an attempt to post a set, the latest posted of each part number
+pub struct PartSet {
+    /// Number of parts, from the lowest numbered part
+    pub count: u16,
+    /// Number, key without the author and data of each part, in order
+    pub parts: Vec<(u16, FeatherDbKey, Vec<u8>)>,
+}
+
+impl PartSet {
+    /// Collect the parts of the attempt to post a set that ends at block `end`, from parts ordered by part number and then by key.
+    ///
+    /// Parts posted more than `window` blocks before the end, or with a different count to the latest part, are from other attempts.
+    pub fn new(parts: &[(PartSetDbKey, Vec<u8>)], end: u32, window: u32) -> PartSet {
+        let start = end.saturating_sub(window);
+        let in_window =
+            |key: &PartSetDbKey| (start..=end).contains(&key.position.block_number.get());
+        let count = parts
+            .iter()
+            .filter(|(key, _)| in_window(key))
+            .filter_map(|(key, value)| Some((key, PartDbValue::decode(value)?.0.count.get())))
+            .max_by(|(a, _), (b, _)| a.position.as_bytes().cmp(b.position.as_bytes()))
+            .map_or(0, |(_, count)| count);
+        let mut set = PartSet {
+            count,
+            parts: vec![],
+        };
+        for (key, value) in parts {
+            let Some((header, _, data)) = PartDbValue::decode(value) else {
+                continue;
+            };
+            if !in_window(key) || header.count.get() != count {
+                continue;
+            }
+            // A part posted again replaces the earlier one.
+            if set
+                .parts
+                .last()
+                .is_some_and(|(part, _, _)| *part == key.part.get())
+            {
+                set.parts.pop();
+            }
+            set.parts
+                .push((key.part.into(), key.position.clone(), data.to_vec()));
+        }
+        set
+    }
+
+    /// Collect the parts of the latest attempt to post a set, from parts ordered by part number and then by key.
+    pub fn latest(parts: &[(PartSetDbKey, Vec<u8>)], window: u32) -> PartSet {
+        let end = parts
+            .iter()
+            .map(|(key, _)| key.position.block_number.get())
+            .max()
+            .unwrap_or(0);
+        PartSet::new(parts, end, window)
+    }
+
+    /// Collect the attempts to post a set that are complete, earliest first, from parts ordered by part number and then by key.
+    pub fn complete(parts: &[(PartSetDbKey, Vec<u8>)], window: u32) -> Vec<PartSet> {
+        let mut ends: Vec<u32> = parts
+            .iter()
+            .map(|(key, _)| key.position.block_number.get())
+            .collect();
+        ends.sort_unstable();
+        ends.dedup();
+        ends.into_iter()
+            .map(|end| PartSet::new(parts, end, window))
+            .filter(PartSet::is_complete)
+            .collect()
+    }
+
+    pub fn first_block(&self) -> u32 {
+        self.parts
+            .iter()
+            .map(|(_, key, _)| key.block_number.get())
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn last_block(&self) -> u32 {
+        self.parts
+            .iter()
+            .map(|(_, key, _)| key.block_number.get())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Are all the parts of the attempt present?
+    pub fn is_complete(&self) -> bool {
+        !self.parts.is_empty() && self.parts.len() == usize::from(self.count)
+    }
+}
+
+/// On-disk format for rejected remark value, followed by the remark
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct RejectedDbValue {
+    pub reason: u8,
+    pub encoding: u8,
+}
+
+impl RejectedDbValue {
+    /// Encode a rejected remark for storage.
+    pub fn encode(reason: RemarkError, remark: &[u8]) -> Vec<u8> {
+        let mut value = RejectedDbValue {
+            reason: reason as u8,
+            encoding: RemarkEncoding::of(remark) as u8,
+        }
+        .as_bytes()
+        .to_vec();
+        value.extend_from_slice(remark);
+        value
+    }
+}
+
+/// Encoding of the remark of a feather
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[repr(u8)]
+pub enum RemarkEncoding {
+    /// Valid UTF-8 text
+    Utf8 = 0,
+    /// Arbitrary bytes, returned hex encoded
+    Binary = 1,
+}
+
+impl RemarkEncoding {
+    /// Encoding to store a remark with.
+    pub fn of(remark: &[u8]) -> RemarkEncoding {
+        match std::str::from_utf8(remark) {
+            Ok(_) => RemarkEncoding::Utf8,
+            Err(_) => RemarkEncoding::Binary,
+        }
+    }
+
+    /// Decode a stored encoding.
+    pub fn from_u8(encoding: u8) -> Option<RemarkEncoding> {
+        match encoding {
+            0 => Some(RemarkEncoding::Utf8),
+            1 => Some(RemarkEncoding::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// On-disk format for extrinsic key
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct ExtrinsicDbKey {
+    pub block_number: U32<BigEndian>,
+    pub index: U16<BigEndian>,
+}
+
+/// On-disk format for extrinsic value, followed by the signer
+#[derive(FromBytes, IntoBytes, Unaligned, PartialEq, Debug, Immutable)]
+#[repr(C)]
+pub struct ExtrinsicDbValue {
+    pub hash: [u8; 32],
+}
+
+/// Account id of 32 bytes, or 20 bytes on Ethereum-style chains
+///
+/// 32 byte account ids are written in SS58 format and 20 byte account ids in hex.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountId(pub Vec<u8>);
+
+impl fmt::Display for AccountId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match <[u8; 32]>::try_from(self.0.as_slice()) {
+            Ok(account_id) => write!(f, "{}", AccountId32(account_id)),
+            Err(_) => write!(f, "0x{}", hex::encode(&self.0)),
+        }
+    }
+}
+
+impl FromStr for AccountId {
+    type Err = IndexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("0x") {
+            Some(hex) => Ok(AccountId(hex::decode(hex)?)),
+            None => AccountId32::from_str(s)
+                .map(|account_id| AccountId(account_id.0.to_vec()))
+                .map_err(|_| IndexError::ParseError),
+        }
+    }
+}
+
+impl Serialize for AccountId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        AccountId::from_str(&s).map_err(de::Error::custom)
+    }
 }
 
 /// Start and end block number for a span of blocks
@@ -51,16 +417,62 @@ pub struct Span {
     pub end: u32,
 }
 
+/// Progress of the indexer
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct IndexerStatus {
+    /// All blocks down to the lowest block to index have been indexed
+    pub backfill_complete: bool,
+    /// Number of times indexing a batch block has failed
+    pub batch_failures: u64,
+    /// Number of times following the head has failed
+    pub head_failures: u64,
+}
+
+/// Indexed spans and progress of the indexer
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub chain: String,
+    pub spans: Vec<Span>,
+    /// Blocks that were skipped because they failed to index too many times
+    pub poisoned: Vec<u32>,
+    #[serde(flatten)]
+    pub indexer: IndexerStatus,
+}
+
 /// JSON request messages
 #[derive(Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum RequestMessage {
-    Status,
+    Status {
+        chain: Option<String>,
+    },
     GetFeathers {
+        chain: Option<String>,
         block_number: u32,
         limit: u32,
-        account_id: Option<AccountId32>,
+        account_id: Option<AccountId>,
         genre: Option<String>,
+        version: Option<u8>,
+        posted_from: Option<u64>,
+        posted_to: Option<u64>,
+    },
+    GetRevisions {
+        chain: Option<String>,
+        block_number: u32,
+        index: u16,
+        sub_index: u16,
+    },
+    GetThread {
+        chain: Option<String>,
+        block_number: u32,
+        index: u16,
+        offset: Option<u32>,
+        limit: u32,
+    },
+    GetPartialFeathers {
+        chain: Option<String>,
+        account_id: Option<AccountId>,
+        limit: u32,
     },
     SizeOnDisk,
 }
@@ -68,10 +480,74 @@ pub enum RequestMessage {
 /// Start and end block number for a span of blocks
 #[derive(Serialize, Debug, Clone, PartialEq, Deserialize)]
 pub struct Feather {
+    pub chain: String,
+    pub block_number: u32,
+    pub block_hash: Option<H256>,
+    pub index: u16,
+    pub sub_index: u16,
+    pub extrinsic_hash: Option<H256>,
+    pub account_id: AccountId,
+    pub signer: AccountId,
+    pub posted_at: Option<u64>,
+    /// Version of the feather protocol the feather was posted with
+    pub version: u8,
+    /// Version of the feather protocol the latest revision was written with, if the feather has been revised
+    pub revision_version: Option<u8>,
+    pub encoding: RemarkEncoding,
+    /// Remark of the latest revision
+    pub remark: String,
+    /// Number of edits and deletions by the author
+    pub revisions: u32,
+    /// The latest revision deletes the feather
+    pub deleted: bool,
+    pub reply_to: Option<Parent>,
+}
+
+/// Extrinsic of the feather a reply is to
+#[derive(Serialize, Debug, Clone, PartialEq, Deserialize)]
+pub struct Parent {
+    pub block_number: u32,
+    pub index: u16,
+}
+
+/// A set of parts that has not formed a feather
+#[derive(Serialize, Debug, Clone, PartialEq, Deserialize)]
+pub struct PartialFeather {
+    pub account_id: AccountId,
+    pub id: String,
+    /// Number of parts in the set
+    pub count: u16,
+    /// Numbers of the parts that have been posted
+    pub parts: Vec<u16>,
+    pub first_block: u32,
+    pub last_block: u32,
+    /// The missing parts can no longer be posted within the window
+    pub expired: bool,
+}
+
+/// A feather with its replies
+#[derive(Serialize, Debug, Clone, PartialEq, Deserialize)]
+pub struct Thread {
+    pub feather: Feather,
+    /// Number of replies to the feather, including any not returned
+    pub reply_count: u32,
+    /// Replies to the first feather of the thread, without their own replies
+    pub replies: Vec<Thread>,
+}
+
+/// A feather as it was posted, or an edit or deletion of it
+#[derive(Serialize, Debug, Clone, PartialEq, Deserialize)]
+pub struct Revision {
     pub block_number: u32,
+    pub block_hash: Option<H256>,
     pub index: u16,
-    pub account_id: AccountId32,
+    pub sub_index: u16,
+    pub extrinsic_hash: Option<H256>,
+    pub posted_at: Option<u64>,
+    pub version: u8,
+    pub encoding: RemarkEncoding,
     pub remark: String,
+    pub deleted: bool,
 }
 
 /// JSON response messages
@@ -79,10 +555,98 @@ pub struct Feather {
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "camelCase")]
 pub enum ResponseMessage {
-    Status(Vec<Span>),
+    Status(Status),
     Feathers(Vec<Feather>),
+    Revisions(Vec<Revision>),
+    Thread(Thread),
+    PartialFeathers(Vec<PartialFeather>),
     Subscribed,
     Unsubscribed,
     SizeOnDisk(u64),
-    //    Error,
+    Error(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parts of a set posted in blocks, as `(part, count, block_number)`, in the order they are stored.
+    fn parts(posted: &[(u16, u16, u32)]) -> Vec<(PartSetDbKey, Vec<u8>)> {
+        let mut parts: Vec<_> = posted
+            .iter()
+            .map(|&(part, count, block_number)| {
+                let key = PartSetDbKey {
+                    part: part.into(),
+                    position: FeatherDbKey {
+                        block_number: block_number.into(),
+                        index: 1.into(),
+                        sub_index: 0.into(),
+                    },
+                };
+                let data = format!("{}@{}", part, block_number);
+                (key, PartDbValue::encode(part, count, "id", data.as_bytes()))
+            })
+            .collect();
+        parts.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+        parts
+    }
+
+    fn blocks(set: &PartSet) -> Vec<u32> {
+        set.parts
+            .iter()
+            .map(|(_, key, _)| key.block_number.get())
+            .collect()
+    }
+
+    #[test]
+    fn parts_within_window_complete() {
+        let parts = parts(&[(1, 2, 100), (2, 2, 150)]);
+        let set = PartSet::latest(&parts, 100);
+        assert!(set.is_complete());
+        assert_eq!(set.first_block(), 100);
+        assert_eq!(set.last_block(), 150);
+    }
+
+    #[test]
+    fn parts_beyond_window_are_incomplete() {
+        let parts = parts(&[(1, 2, 100), (2, 2, 250)]);
+        let set = PartSet::latest(&parts, 100);
+        assert!(!set.is_complete());
+        assert_eq!(blocks(&set), [250]);
+    }
+
+    #[test]
+    fn retry_after_window_completes() {
+        let parts = parts(&[(1, 2, 100), (1, 2, 300), (2, 2, 310)]);
+        let set = PartSet::latest(&parts, 100);
+        assert!(set.is_complete());
+        assert_eq!(blocks(&set), [300, 310]);
+    }
+
+    #[test]
+    fn earlier_attempt_is_found_by_its_end() {
+        let parts = parts(&[(1, 2, 100), (2, 2, 110), (1, 3, 500)]);
+        assert!(!PartSet::latest(&parts, 100).is_complete());
+        let set = PartSet::new(&parts, 110, 100);
+        assert!(set.is_complete());
+        assert_eq!(blocks(&set), [100, 110]);
+    }
+
+    #[test]
+    fn parts_with_another_count_are_left_out() {
+        let parts = parts(&[(1, 3, 100), (1, 2, 105), (2, 2, 110)]);
+        let set = PartSet::latest(&parts, 100);
+        assert!(set.is_complete());
+        assert_eq!(set.count, 2);
+        assert_eq!(blocks(&set), [105, 110]);
+    }
+
+    #[test]
+    fn complete_attempts_are_earliest_first() {
+        let parts = parts(&[(1, 2, 100), (2, 2, 101), (2, 2, 150), (1, 2, 900)]);
+        let complete = PartSet::complete(&parts, 100);
+        assert_eq!(complete.len(), 2);
+        assert_eq!(blocks(&complete[0]), [100, 101]);
+        assert_eq!(blocks(&complete[1]), [100, 150]);
+    }
 }
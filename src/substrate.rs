@@ -1,56 +1,403 @@
-use ahash::AHashMap;
-use futures::future;
+use ahash::{AHashMap, AHashSet};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
 use num_format::{Locale, ToFormattedString};
+use sled::Transactional;
 use sled::Tree;
-use subxt::ext::scale_value::At;
-use subxt::utils::AccountId32;
-use subxt::{OnlineClient, PolkadotConfig, blocks::Block, ext::subxt_rpcs::LegacyRpcMethods};
+use sled::transaction::ConflictableTransactionError;
+use std::sync::Mutex;
+use subxt::backend::StreamOfResults;
+use subxt::backend::rpc::RpcClient;
+use subxt::client::RuntimeVersion;
+use subxt::config::{Hasher, substrate::BlakeTwo256};
+use subxt::events::Phase;
+use subxt::ext::codec::{Compact, Decode, Encode};
+use subxt::ext::scale_value::{At, Composite, Value, ValueDef};
+use subxt::utils::H256;
+use subxt::{Metadata, OnlineClient, blocks::Block, ext::subxt_rpcs::LegacyRpcMethods};
 use tokio::sync::watch;
 use tokio::time;
 use tokio::time::MissedTickBehavior;
 use tokio::time::{Duration, Instant};
-use tracing_log::log::{debug, error, info};
+use tracing_log::log::{debug, error, info, warn};
 use zerocopy::{FromBytes, IntoBytes};
 
-use crate::Trees;
+use crate::config::IndexerConfig;
+use crate::remark::{FeatherOp, FeatherRemark, RemarkError};
+use crate::{ChainSettings, Trees};
+use std::collections::BTreeMap;
 
 use crate::shared::*;
 
+/// Number of times to try indexing a batch block before skipping it
+const MAX_BATCH_ATTEMPTS: u32 = 5;
+/// Delay before the first retry of a failed batch block, doubled for each further retry
+const BATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Delay before reconnecting to a node, doubled for each consecutive failed connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Number of times to try indexing a head block before skipping it
+const MAX_HEAD_ATTEMPTS: u32 = 5;
+
+/// Subscription to new head blocks
+type BlockStream<C> = StreamOfResults<Block<C, OnlineClient<C>>>;
+
+/// Database writes for indexing or retracting a block, held back until they can be committed with the span change that covers the block
+#[derive(Default)]
+struct BlockWrites {
+    feather: sled::Batch,
+    extrinsic: sled::Batch,
+    failed: sled::Batch,
+    rejected: sled::Batch,
+    revision: sled::Batch,
+    revision_index: sled::Batch,
+    reply: sled::Batch,
+    part: sled::Batch,
+    part_set: sled::Batch,
+    timestamp: sled::Batch,
+    block_hash: sled::Batch,
+    /// Part set key and value of each part written, to form feathers from when committed
+    parts: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Part set keys of the parts removed
+    retracted_parts: Vec<Vec<u8>>,
+}
+
+/// Blocks written by following the head, or the block that failed and the blocks below it that were written
+type HeadResult = Result<(u32, u32, Vec<BlockWrites>), (Option<u32>, IndexError, Vec<BlockWrites>)>;
+
 #[allow(clippy::type_complexity)]
-pub struct Indexer {
+pub struct Indexer<C: IndexerConfig> {
     trees: Trees,
-    api: Option<OnlineClient<PolkadotConfig>>,
-    rpc: Option<LegacyRpcMethods<PolkadotConfig>>,
+    api: Option<OnlineClient<C>>,
+    rpc_client: RpcClient,
+    rpc: Option<LegacyRpcMethods<C>>,
+    best: bool,
+    /// Clients for each runtime spec version that has been encountered
+    clients: Mutex<AHashMap<u32, OnlineClient<C>>>,
 }
 
-impl Indexer {
-    fn new(
-        trees: Trees,
-        api: OnlineClient<PolkadotConfig>,
-        rpc: LegacyRpcMethods<PolkadotConfig>,
-    ) -> Self {
+impl<C: IndexerConfig> Indexer<C> {
+    fn new(trees: Trees, api: OnlineClient<C>, rpc_client: RpcClient, best: bool) -> Self {
+        let mut clients = AHashMap::new();
+        clients.insert(api.runtime_version().spec_version, api.clone());
         Indexer {
             trees,
             api: Some(api),
-            rpc: Some(rpc),
+            rpc: Some(LegacyRpcMethods::new(rpc_client.clone())),
+            rpc_client,
+            best,
+            clients: Mutex::new(clients),
+        }
+    }
+
+    /// Get a client that decodes with the metadata of the runtime that was active at a block.
+    async fn client_at(&self, block_hash: H256) -> Result<OnlineClient<C>, IndexError> {
+        let api = self.api.as_ref().unwrap();
+        let rpc = self.rpc.as_ref().unwrap();
+
+        let version = rpc.state_get_runtime_version(Some(block_hash)).await?;
+        if let Some(client) = self.clients.lock().unwrap().get(&version.spec_version) {
+            return Ok(client.clone());
         }
+        let metadata = rpc
+            .state_get_metadata(Some(block_hash))
+            .await?
+            .to_frame_metadata()?;
+        let client = OnlineClient::from_rpc_client_with(
+            api.genesis_hash(),
+            RuntimeVersion {
+                spec_version: version.spec_version,
+                transaction_version: version.transaction_version,
+            },
+            Metadata::try_from(metadata)?,
+            self.rpc_client.clone(),
+        )?;
+        info!(
+            "📦 Loaded metadata for runtime version {}",
+            version.spec_version
+        );
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(version.spec_version, client.clone());
+        Ok(client)
     }
 
+    /// Index the next head block. `next_block` is the first block that has not been indexed by following the head yet, so any blocks the subscription skipped are indexed first.
+    ///
+    /// On failure, returns the block that could not be indexed, if any, along with the writes for the blocks below it.
     async fn index_head(
         &self,
-        next: impl Future<
-            Output = Option<
-                Result<Block<PolkadotConfig, OnlineClient<PolkadotConfig>>, subxt::Error>,
-            >,
-        >,
-    ) -> Result<(u32, u32), IndexError> {
-        let block = next.await.unwrap()?;
-        self.index_block(block.number()).await
+        next: impl Future<Output = Option<Result<Block<C, OnlineClient<C>>, subxt::Error>>>,
+        next_block: u32,
+    ) -> HeadResult {
+        let block = match next.await {
+            Some(Ok(block)) => block,
+            Some(Err(error)) => return Err((None, error.into(), vec![])),
+            None => return Err((None, IndexError::SubscriptionEnded, vec![])),
+        };
+        let block_number = block.number();
+        let block_hash = block.hash();
+        if !self.best {
+            // Finalized blocks can't be retracted, so skipped blocks can be looked up by number.
+            let mut feathers = 0;
+            let mut blocks = vec![];
+            for number in next_block..block_number {
+                debug!(
+                    "✨ #{}: skipped by subscription",
+                    number.to_formatted_string(&Locale::en)
+                );
+                let mut writes = BlockWrites::default();
+                match self.index_block(number, &mut writes).await {
+                    Ok((_, count)) => feathers += count,
+                    Err(error) => return Err((Some(number), error, blocks)),
+                }
+                blocks.push(writes);
+            }
+            let mut writes = BlockWrites::default();
+            match self
+                .index_block_at(block_number, block_hash, &mut writes)
+                .await
+            {
+                Ok((_, count)) => feathers += count,
+                Err(error) => return Err((Some(block_number), error, blocks)),
+            }
+            blocks.push(writes);
+            return Ok((block_number, feathers, blocks));
+        }
+        // Blocks before finalization can be retracted. Roll back any blocks that are not ancestors of the new best block and index the new branch in their place.
+        let mut retractions = BlockWrites::default();
+        let reindex = match self
+            .rollback_fork(
+                block_number,
+                block_hash,
+                block.header().parent_hash,
+                next_block,
+                &mut retractions,
+            )
+            .await
+        {
+            Ok(reindex) => reindex,
+            Err(error) => return Err((None, error, vec![])),
+        };
+        let mut feathers = 0;
+        let mut blocks = vec![retractions];
+        for (number, hash) in reindex
+            .into_iter()
+            .rev()
+            .chain([(block_number, block_hash)])
+        {
+            let mut writes = BlockWrites::default();
+            match self.index_block_at(number, hash, &mut writes).await {
+                Ok((_, count)) => feathers += count,
+                Err(error) => return Err((Some(number), error, blocks)),
+            }
+            blocks.push(writes);
+        }
+        Ok((block_number, feathers, blocks))
     }
 
-    async fn index_block(&self, block_number: u32) -> Result<(u32, u32), IndexError> {
-        let mut feathers = 0;
-        let api = self.api.as_ref().unwrap();
+    /// Remove all indexed blocks that are not ancestors of a new best block. Returns the blocks of the new branch that need to be indexed, newest first, including any from `next_block` onwards that were skipped by the subscription.
+    async fn rollback_fork(
+        &self,
+        block_number: u32,
+        block_hash: H256,
+        parent_hash: H256,
+        next_block: u32,
+        writes: &mut BlockWrites,
+    ) -> Result<Vec<(u32, H256)>, IndexError> {
+        let rpc = self.rpc.as_ref().unwrap();
+        // Any block at the same height or above the new best block has been retracted.
+        for (key, value) in self
+            .trees
+            .block_hash
+            .range(block_number.to_be_bytes()..)
+            .flatten()
+        {
+            let number = u32::from_be_bytes(key.as_ref().try_into().unwrap());
+            if number == block_number && value.as_ref() == block_hash.as_bytes() {
+                continue;
+            }
+            self.retract_block(number, writes);
+        }
+        // Walk back the new branch until it joins the indexed chain.
+        let mut reindex = vec![];
+        let mut number = block_number;
+        let mut hash = parent_hash;
+        while number > 0 {
+            number -= 1;
+            match self.trees.block_hash.get(number.to_be_bytes())? {
+                Some(indexed_hash) if indexed_hash.as_ref() != hash.as_bytes() => {
+                    self.retract_block(number, writes);
+                }
+                None if number >= next_block => {
+                    debug!(
+                        "✨ #{}: skipped by subscription",
+                        number.to_formatted_string(&Locale::en)
+                    );
+                }
+                _ => break,
+            }
+            reindex.push((number, hash));
+            hash = match rpc.chain_get_header(Some(hash)).await? {
+                Some(header) => header.parent_hash,
+                None => return Err(IndexError::BlockNotFound(number)),
+            };
+        }
+        Ok(reindex)
+    }
+
+    /// Remove a retracted block and all the feathers that were indexed from it.
+    fn retract_block(&self, block_number: u32, writes: &mut BlockWrites) {
+        let mut feathers: u32 = 0;
+        for (key, value) in self
+            .trees
+            .feather
+            .scan_prefix(block_number.to_be_bytes())
+            .flatten()
+        {
+            if let Some(parent) = FeatherDbValue::decode(&value)
+                .and_then(|(_, _, remark)| FeatherRemark::parse(remark).ok().flatten())
+                .and_then(|feather| match feather.op {
+                    FeatherOp::Reply(parent, _) => Some(parent),
+                    _ => None,
+                })
+                && let Ok((reply, author)) = FeatherDbKey::read_from_prefix(&key)
+            {
+                let mut reply_key = ReplyDbKey {
+                    block_number: parent.block_number.into(),
+                    index: parent.index.into(),
+                    reply,
+                }
+                .as_bytes()
+                .to_vec();
+                reply_key.extend_from_slice(author);
+                writes.reply.remove(reply_key);
+            }
+            writes.feather.remove(key);
+            feathers += 1;
+        }
+        for (key, value) in self
+            .trees
+            .revision
+            .scan_prefix(block_number.to_be_bytes())
+            .flatten()
+        {
+            if let (Ok((revision, author)), Ok((target, _))) = (
+                FeatherDbKey::read_from_prefix(&key),
+                RevisionDbValue::read_from_prefix(&value),
+            ) {
+                let mut index_key = RevisionIndexDbKey {
+                    block_number: target.block_number,
+                    index: target.index,
+                    revision,
+                }
+                .as_bytes()
+                .to_vec();
+                index_key.extend_from_slice(author);
+                writes.revision_index.remove(index_key);
+            }
+            writes.revision.remove(key);
+            feathers += 1;
+        }
+        for (key, value) in self
+            .trees
+            .part
+            .scan_prefix(block_number.to_be_bytes())
+            .flatten()
+        {
+            if let (Ok((position, author)), Some((header, id, _))) = (
+                FeatherDbKey::read_from_prefix(&key),
+                PartDbValue::decode(&value),
+            ) {
+                let mut set_key = PartSetDbKey::prefix(author, id);
+                set_key.extend_from_slice(
+                    PartSetDbKey {
+                        part: header.part,
+                        position,
+                    }
+                    .as_bytes(),
+                );
+                writes.part_set.remove(set_key.as_slice());
+                writes.retracted_parts.push(set_key);
+            }
+            writes.part.remove(key);
+        }
+        for (tree, batch) in [
+            (&self.trees.extrinsic, &mut writes.extrinsic),
+            (&self.trees.failed, &mut writes.failed),
+            (&self.trees.rejected, &mut writes.rejected),
+        ] {
+            for (key, _) in tree.scan_prefix(block_number.to_be_bytes()).flatten() {
+                batch.remove(key);
+            }
+        }
+        writes.block_hash.remove(&block_number.to_be_bytes());
+        writes.timestamp.remove(&block_number.to_be_bytes());
+        info!(
+            "🔀 #{}: retracted, {} feathers removed",
+            block_number.to_formatted_string(&Locale::en),
+            feathers.to_formatted_string(&Locale::en),
+        );
+    }
+
+    /// Index a block for the batch, first waiting for a delay if the block is being retried.
+    async fn index_batch_block(
+        &self,
+        block_number: u32,
+        delay: Duration,
+    ) -> (u32, Result<(u32, BlockWrites), IndexError>) {
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+        let mut writes = BlockWrites::default();
+        let result = self
+            .index_block(block_number, &mut writes)
+            .await
+            .map(|(_, feathers)| (feathers, writes));
+        (block_number, result)
+    }
+
+    /// Look up the accounts of indices that are missing, in the state of a block.
+    async fn lookup_indices(
+        &self,
+        api: &OnlineClient<C>,
+        block_hash: H256,
+        indices: &mut AccountIndices,
+    ) -> Result<(), IndexError> {
+        for index in std::mem::take(&mut indices.missing) {
+            if indices.accounts.contains_key(&index) {
+                continue;
+            }
+            // Chains without the Indices pallet have no indices to look up.
+            let account_id = match api.metadata().pallet_by_name("Indices") {
+                Some(_) => {
+                    let query = subxt::dynamic::storage(
+                        "Indices",
+                        "Accounts",
+                        vec![Value::u128(index.into())],
+                    );
+                    match api.storage().at(block_hash).fetch(&query).await? {
+                        // The account is stored with its deposit and whether the index is frozen.
+                        Some(value) => value
+                            .to_value()
+                            .map_err(subxt::Error::from)?
+                            .at(0)
+                            .and_then(account_id_bytes),
+                        None => None,
+                    }
+                }
+                None => None,
+            };
+            indices.accounts.insert(index, account_id);
+        }
+        Ok(())
+    }
+
+    async fn index_block(
+        &self,
+        block_number: u32,
+        writes: &mut BlockWrites,
+    ) -> Result<(u32, u32), IndexError> {
         let rpc = self.rpc.as_ref().unwrap();
 
         let block_hash = match rpc.chain_get_block_hash(Some(block_number.into())).await? {
@@ -58,65 +405,623 @@ impl Indexer {
             None => return Err(IndexError::BlockNotFound(block_number)),
         };
 
+        self.index_block_at(block_number, block_hash, writes).await
+    }
+
+    async fn index_block_at(
+        &self,
+        block_number: u32,
+        block_hash: H256,
+        writes: &mut BlockWrites,
+    ) -> Result<(u32, u32), IndexError> {
+        let mut feathers = 0;
+        let rpc = self.rpc.as_ref().unwrap();
+
+        let header = match rpc.chain_get_header(Some(block_hash)).await? {
+            Some(header) => header,
+            None => return Err(IndexError::BlockNotFound(block_number)),
+        };
+        // The block was executed by the runtime of its parent, so decode it with that metadata.
+        let runtime_hash = match block_number {
+            0 => block_hash,
+            _ => header.parent_hash,
+        };
+        let api = self.client_at(runtime_hash).await?;
+        let mut indices = AccountIndices::default();
+
         let block = api.blocks().at(block_hash).await?;
+        // Find out which extrinsics were dispatched successfully, and which of the calls nested in them were executed.
+        let mut succeeded = AHashSet::new();
+        let mut dispatch_events: AHashMap<u32, Vec<DispatchEvent>> = AHashMap::new();
+        for event in block.events().await?.iter() {
+            let event = event?;
+            let Phase::ApplyExtrinsic(index) = event.phase() else {
+                continue;
+            };
+            if event.pallet_name() == "System" && event.variant_name() == "ExtrinsicSuccess" {
+                succeeded.insert(index);
+            } else if let Some(dispatch_event) = DispatchEvent::from_event(
+                event.pallet_name(),
+                event.variant_name(),
+                &event.field_values()?,
+            ) {
+                dispatch_events
+                    .entry(index)
+                    .or_default()
+                    .push(dispatch_event);
+            }
+        }
         let extrinsics = block.extrinsics().await?;
-        // Look for remarks.
         for (i, xt) in extrinsics.iter().enumerate() {
+            // Feather keys can only refer to the first 65,536 extrinsics of a block.
+            let Ok(index) = u16::try_from(i) else {
+                warn!(
+                    "#{}: too many extrinsics, skipping those after {}",
+                    block_number.to_formatted_string(&Locale::en),
+                    u16::MAX
+                );
+                break;
+            };
             let variant_name = xt.variant_name()?;
-            if (xt.pallet_name()? == "System")
-                && (variant_name == "remark" || variant_name == "remark_with_event")
-            {
-                let field_values = xt.field_values()?;
+            // Record when the block was authored.
+            if xt.pallet_name()? == "Timestamp" && variant_name == "set" {
+                if let Some(now) = xt.field_values()?.at("now").and_then(|now| now.as_u128()) {
+                    let timestamp: u64 = now.try_into().map_err(|_| IndexError::ParseError)?;
+                    writes
+                        .timestamp
+                        .insert(&block_number.to_be_bytes(), &timestamp.to_be_bytes());
+                }
+                continue;
+            }
+            let Some(address) = xt.address_bytes() else {
+                continue;
+            };
+            let Some(address) = decode_address(address, C::BARE_ADDRESS) else {
+                warn!(
+                    "#{}: extrinsic {} has an unsupported address",
+                    block_number.to_formatted_string(&Locale::en),
+                    i
+                );
+                continue;
+            };
+            let signer = match address {
+                Address::Id(account_id) => account_id,
+                Address::Index(index) => {
+                    indices.missing.push(index);
+                    self.lookup_indices(&api, runtime_hash, &mut indices)
+                        .await?;
+                    match indices.get(index) {
+                        Some(account_id) => account_id,
+                        None => {
+                            debug!(
+                                "#{}: extrinsic {} signed by unassigned index {}",
+                                block_number.to_formatted_string(&Locale::en),
+                                i,
+                                index
+                            );
+                            continue;
+                        }
+                    }
+                }
+            };
+            // Look for remarks, including those nested inside batches, proxies and multisigs. Look up any indices they use and search again.
+            let fields = xt.field_values()?;
+            let remarks = loop {
+                let mut remarks = vec![];
+                let mut events = DispatchEvents {
+                    events: dispatch_events.remove(&xt.index()).unwrap_or_default(),
+                    next: 0,
+                };
+                find_remarks(
+                    signer.clone(),
+                    xt.pallet_name()?,
+                    variant_name,
+                    &fields,
+                    succeeded.contains(&xt.index()),
+                    &mut indices,
+                    &mut events,
+                    &mut remarks,
+                );
+                dispatch_events.insert(xt.index(), events.events);
+                if indices.missing.is_empty() {
+                    break remarks;
+                }
+                self.lookup_indices(&api, runtime_hash, &mut indices)
+                    .await?;
+            };
 
-                if let Some(value) = field_values.at("remark") {
-                    let mut remark = String::new();
+            // Feather keys can only refer to the first 65,536 remarks of an extrinsic.
+            if remarks.len() > usize::from(u16::MAX) + 1 {
+                warn!(
+                    "#{}: extrinsic {} has too many remarks, skipping those after {}",
+                    block_number.to_formatted_string(&Locale::en),
+                    i,
+                    u16::MAX
+                );
+            }
+            for (sub_index, found) in (0..=u16::MAX).zip(remarks) {
+                let key = FeatherDbKey {
+                    block_number: block_number.into(),
+                    index: index.into(),
+                    sub_index: sub_index.into(),
+                };
 
-                    let mut p = 0;
+                let feather = match check_remark(&found.remark, &key) {
+                    Ok(Some(feather)) => feather,
+                    Ok(None) => continue,
+                    // Keep malformed feathers with the reason they were rejected.
+                    Err(reason) => {
+                        warn!(
+                            "#{}: extrinsic {} has a malformed feather: {}",
+                            block_number.to_formatted_string(&Locale::en),
+                            i,
+                            reason
+                        );
+                        writes.rejected.insert(
+                            [key.as_bytes(), &found.origin].concat(),
+                            RejectedDbValue::encode(reason, &found.remark),
+                        );
+                        continue;
+                    }
+                };
 
-                    while let Some(char) = value.at(p) {
-                        let c: u8 = char.as_u128().unwrap().try_into().unwrap();
-                        remark.push(c.into());
-                        p += 1;
+                info!(
+                    "AccountId: {:#?}",
+                    AccountId(found.origin.clone()).to_string()
+                );
+                info!("Version: {:#?}", feather.version);
+                match &feather.op {
+                    FeatherOp::Post(_) => {}
+                    FeatherOp::Reply(parent, _) => {
+                        info!("Reply to: #{}-{}", parent.block_number, parent.index);
                     }
+                    FeatherOp::Edit(target, _) | FeatherOp::Delete(target) => {
+                        info!("Revises: #{}-{}", target.block_number, target.index);
+                    }
+                    FeatherOp::Part(part) => {
+                        info!("Part: {} of {} of {:#?}", part.part, part.count, part.id);
+                    }
+                }
+                if let Some(post) = feather.post() {
+                    info!("Genre: {:#?}", post.genre);
+                    info!("Title: {:#?}", post.title);
+                    info!("Content: {:#?}", String::from_utf8_lossy(&post.content));
+                }
 
-                    info!("remark: {:#?}", remark);
+                // Keep feathers from failed extrinsics and calls separately for diagnostics.
+                if !found.executed {
+                    debug!(
+                        "#{}: extrinsic {} failed, feather not indexed",
+                        block_number.to_formatted_string(&Locale::en),
+                        i
+                    );
+                    writes.failed.insert(
+                        [key.as_bytes(), &found.origin].concat(),
+                        FeatherDbValue::encode(feather.version, &found.remark),
+                    );
+                    continue;
+                }
+                write_feather(writes, key, &found.origin, feather, &found.remark);
 
-                    let components: Vec<&str> = remark.split("::").collect();
+                let key = ExtrinsicDbKey {
+                    block_number: block_number.into(),
+                    index: index.into(),
+                };
+                let value = ExtrinsicDbValue { hash: xt.hash().0 };
+                let mut value = value.as_bytes().to_vec();
+                value.extend_from_slice(&signer);
+                writes.extrinsic.insert(key.as_bytes(), value);
 
-                    if components[0] != "FEATHER" {
-                        continue;
-                    }
+                feathers += 1;
+            }
+        }
+
+        writes
+            .block_hash
+            .insert(&block_number.to_be_bytes(), block_hash.as_bytes());
+
+        Ok((block_number, feathers))
+    }
+}
+
+/// A remark found inside an extrinsic
+struct FoundRemark {
+    /// Account the remark was made on behalf of
+    origin: Vec<u8>,
+    remark: Vec<u8>,
+    /// Whether the call that made the remark was executed successfully
+    executed: bool,
+}
 
-                    if let Some(address) = xt.address_bytes() {
-                        let account_id_bytes: [u8; 32] = address[1..33].try_into().unwrap(); // leave out the first byte for some reason
-                        let account_id: AccountId32 = account_id_bytes.into();
-
-                        let genre = components[1];
-                        let title = components[2];
-                        let content = components[3];
-
-                        info!("AccountId: {:#?}", account_id.to_string());
-                        info!("Genre: {:#?}", genre);
-                        info!("Title: {:#?}", title);
-                        info!("Content: {:#?}", content);
-
-                        let key = FeatherDbKey {
-                            block_number: block_number.into(),
-                            index: i.try_into().unwrap(),
-                            account_id: account_id_bytes,
-                        };
-                        self.trees
-                            .feather
-                            .insert(key.as_bytes(), remark.as_bytes())?;
-
-                        feathers += 1;
+/// Event that reports the result of a call nested in an extrinsic
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DispatchEvent {
+    /// `Proxy.ProxyExecuted`, and whether the call succeeded
+    ProxyExecuted(bool),
+    /// `Multisig.MultisigExecuted`, and whether the call succeeded
+    MultisigExecuted(bool),
+    /// `Multisig.NewMultisig` or `Multisig.MultisigApproval`, the call is waiting for more approvals
+    MultisigApproval,
+    /// `Utility.ItemCompleted`, a call in a batch succeeded
+    ItemCompleted,
+    /// `Utility.ItemFailed`, a call in a `force_batch` failed
+    ItemFailed,
+    /// `Utility.BatchInterrupted`, and the index of the call in the batch that failed
+    BatchInterrupted(u32),
+    /// `Utility.BatchCompleted` or `Utility.BatchCompletedWithErrors`
+    BatchCompleted,
+}
+
+impl DispatchEvent {
+    /// Read an event that reports the result of a nested call, if it is one.
+    fn from_event(pallet: &str, variant: &str, fields: &Composite<u32>) -> Option<DispatchEvent> {
+        let is_ok = || {
+            matches!(
+                fields.at("result"),
+                Some(Value {
+                    value: ValueDef::Variant(result),
+                    ..
+                }) if result.name == "Ok"
+            )
+        };
+        Some(match (pallet, variant) {
+            ("Proxy", "ProxyExecuted") => DispatchEvent::ProxyExecuted(is_ok()),
+            ("Multisig", "MultisigExecuted") => DispatchEvent::MultisigExecuted(is_ok()),
+            ("Multisig", "NewMultisig" | "MultisigApproval") => DispatchEvent::MultisigApproval,
+            ("Utility", "ItemCompleted") => DispatchEvent::ItemCompleted,
+            ("Utility", "ItemFailed") => DispatchEvent::ItemFailed,
+            ("Utility", "BatchInterrupted") => DispatchEvent::BatchInterrupted(
+                fields
+                    .at("index")
+                    .and_then(|index| index.as_u128())
+                    .and_then(|index| index.try_into().ok())?,
+            ),
+            ("Utility", "BatchCompleted" | "BatchCompletedWithErrors") => {
+                DispatchEvent::BatchCompleted
+            }
+            _ => return None,
+        })
+    }
+
+    /// Did the call the event reports on succeed?
+    fn is_ok(self) -> bool {
+        matches!(
+            self,
+            DispatchEvent::ProxyExecuted(true)
+                | DispatchEvent::MultisigExecuted(true)
+                | DispatchEvent::ItemCompleted
+        )
+    }
+}
+
+/// Events that report the results of the calls nested in an extrinsic, in the order they were emitted
+///
+/// A call reports its result after the events of the calls nested inside it, and the events of a call that failed are reverted.
+struct DispatchEvents {
+    events: Vec<DispatchEvent>,
+    /// Position of the next event to match with a call
+    next: usize,
+}
+
+impl DispatchEvents {
+    /// Take the next event if it matches.
+    fn next_if(&mut self, matches: impl Fn(DispatchEvent) -> bool) -> Option<DispatchEvent> {
+        let event = *self
+            .events
+            .get(self.next)
+            .filter(|event| matches(**event))?;
+        self.next += 1;
+        Some(event)
+    }
+}
+
+/// Address an extrinsic was signed with
+enum Address {
+    Id(Vec<u8>),
+    Index(u32),
+}
+
+/// Decode the address of a signed extrinsic. This is either a bare 20 or 32 byte account id, or a `MultiAddress`, depending on the config of the chain.
+fn decode_address(mut bytes: &[u8], bare: bool) -> Option<Address> {
+    if bare {
+        return matches!(bytes.len(), 20 | 32).then(|| Address::Id(bytes.to_vec()));
+    }
+    let address = match u8::decode(&mut bytes).ok()? {
+        // Id and Address32
+        0 | 3 => Address::Id(<[u8; 32]>::decode(&mut bytes).ok()?.to_vec()),
+        1 => Address::Index(Compact::<u32>::decode(&mut bytes).ok()?.0),
+        2 => Address::Id(
+            Vec::<u8>::decode(&mut bytes)
+                .ok()
+                .filter(|raw| matches!(raw.len(), 20 | 32))?,
+        ),
+        4 => Address::Id(<[u8; 20]>::decode(&mut bytes).ok()?.to_vec()),
+        _ => return None,
+    };
+    bytes.is_empty().then_some(address)
+}
+
+/// Accounts of the `Indices` indices used in a block
+#[derive(Default)]
+struct AccountIndices {
+    /// Account of each index that has been looked up, if the index is assigned
+    accounts: AHashMap<u32, Option<Vec<u8>>>,
+    /// Indices that have been used but not looked up yet
+    missing: Vec<u32>,
+}
+
+impl AccountIndices {
+    /// Get the account of an index, recording the index as missing if it has not been looked up.
+    fn get(&mut self, index: u32) -> Option<Vec<u8>> {
+        match self.accounts.get(&index) {
+            Some(account_id) => account_id.clone(),
+            None => {
+                self.missing.push(index);
+                None
+            }
+        }
+    }
+}
+
+/// Find all the remarks made by a call, looking inside batches, proxies and multisigs. Remarks are only executed if the call was executed and the events show that the calls they are nested in were too.
+#[allow(clippy::too_many_arguments)]
+fn find_remarks(
+    origin: Vec<u8>,
+    pallet: &str,
+    call: &str,
+    fields: &Composite<u32>,
+    executed: bool,
+    indices: &mut AccountIndices,
+    events: &mut DispatchEvents,
+    remarks: &mut Vec<FoundRemark>,
+) {
+    match (pallet, call) {
+        ("System", "remark" | "remark_with_event") => {
+            if let Some(remark) = fields.at("remark").and_then(value_bytes) {
+                remarks.push(FoundRemark {
+                    origin,
+                    remark,
+                    executed,
+                });
+            }
+        }
+        ("Utility", "batch" | "batch_all" | "force_batch") => {
+            if let Some(Value {
+                value: ValueDef::Composite(calls),
+                ..
+            }) = fields.at("calls")
+            {
+                find_batch_remarks(origin, calls, executed, indices, events, remarks);
+            }
+        }
+        ("Proxy", "proxy" | "proxy_announced") => {
+            // The call is dispatched from the proxied account.
+            let real = fields
+                .at("real")
+                .and_then(|real| multi_address_account_id(real, indices));
+            match (real, fields.at("call")) {
+                (Some(real), Some(call)) => {
+                    find_dispatched_remarks(
+                        real,
+                        call,
+                        executed,
+                        indices,
+                        events,
+                        remarks,
+                        |event| matches!(event, DispatchEvent::ProxyExecuted(_)),
+                    );
+                }
+                // Indices are looked up before searching again.
+                _ if !indices.missing.is_empty() => {}
+                _ => debug!("Proxy call with an account or call that can't be decoded"),
+            }
+        }
+        ("Multisig", "as_multi" | "as_multi_threshold_1") => {
+            // The call is dispatched from the multisig account.
+            let threshold = match call {
+                "as_multi" => fields
+                    .at("threshold")
+                    .and_then(|threshold| threshold.as_u128())
+                    .and_then(|threshold| threshold.try_into().ok()),
+                _ => Some(1),
+            };
+            if let Some(threshold) = threshold
+                && let Some(Value {
+                    value: ValueDef::Composite(others),
+                    ..
+                }) = fields.at("other_signatories")
+                && let Some(mut signatories) = others
+                    .values()
+                    .map(account_id_bytes)
+                    .collect::<Option<Vec<_>>>()
+                && let Some(call) = fields.at("call")
+            {
+                signatories.push(origin);
+                if let Some(multisig) = multi_account_id(signatories, threshold) {
+                    // With a threshold of 1 the call is executed straight away and no event reports it.
+                    if threshold == 1 {
+                        find_nested_remarks(multisig, call, executed, indices, events, remarks);
+                    } else {
+                        find_dispatched_remarks(
+                            multisig,
+                            call,
+                            executed,
+                            indices,
+                            events,
+                            remarks,
+                            |event| {
+                                matches!(
+                                    event,
+                                    DispatchEvent::MultisigExecuted(_)
+                                        | DispatchEvent::MultisigApproval
+                                )
+                            },
+                        );
                     }
                 }
             }
         }
+        _ => {}
+    }
+}
 
-        Ok((block_number, feathers))
+/// Find all the remarks made by a call that has been decoded as a value of the runtime call enum.
+fn find_nested_remarks(
+    origin: Vec<u8>,
+    call: &Value<u32>,
+    executed: bool,
+    indices: &mut AccountIndices,
+    events: &mut DispatchEvents,
+    remarks: &mut Vec<FoundRemark>,
+) {
+    if let ValueDef::Variant(pallet) = &call.value
+        && let Some(Value {
+            value: ValueDef::Variant(call),
+            ..
+        }) = pallet.values.values().next()
+    {
+        find_remarks(
+            origin,
+            &pallet.name,
+            &call.name,
+            &call.values,
+            executed,
+            indices,
+            events,
+            remarks,
+        );
+    }
+}
+
+/// Find all the remarks made by the calls in a batch. Calls that failed, and calls after one that interrupted the batch, were not executed.
+fn find_batch_remarks(
+    origin: Vec<u8>,
+    calls: &Composite<u32>,
+    mut executed: bool,
+    indices: &mut AccountIndices,
+    events: &mut DispatchEvents,
+    remarks: &mut Vec<FoundRemark>,
+) {
+    for (i, call) in calls.values().enumerate() {
+        if !executed {
+            find_nested_remarks(origin.clone(), call, false, indices, events, remarks);
+            continue;
+        }
+        let (next, found) = (events.next, remarks.len());
+        find_nested_remarks(origin.clone(), call, true, indices, events, remarks);
+        if events
+            .next_if(|event| event == DispatchEvent::ItemCompleted)
+            .is_some()
+        {
+            continue;
+        }
+        // A call that failed emitted no events of its own, so its failure is reported first.
+        let completed = events.next;
+        events.next = next;
+        match events.next_if(|event| {
+            event == DispatchEvent::ItemFailed
+                || event == DispatchEvent::BatchInterrupted(i.try_into().unwrap_or(u32::MAX))
+        }) {
+            Some(event) => {
+                for remark in &mut remarks[found..] {
+                    remark.executed = false;
+                }
+                executed = event == DispatchEvent::ItemFailed;
+            }
+            // Older runtimes don't report calls that completed.
+            None => events.next = completed,
+        }
+    }
+    if executed {
+        events.next_if(|event| event == DispatchEvent::BatchCompleted);
+    }
+}
+
+/// Find all the remarks made by a nested call whose result is reported by an event after the events of the call. Returns the event, if it is next.
+///
+/// If the event doesn't show that the call succeeded, the call emitted no events of its own and its remarks were not executed.
+#[allow(clippy::too_many_arguments)]
+fn find_dispatched_remarks(
+    origin: Vec<u8>,
+    call: &Value<u32>,
+    executed: bool,
+    indices: &mut AccountIndices,
+    events: &mut DispatchEvents,
+    remarks: &mut Vec<FoundRemark>,
+    is_result: impl Fn(DispatchEvent) -> bool,
+) -> Option<DispatchEvent> {
+    if !executed {
+        find_nested_remarks(origin, call, false, indices, events, remarks);
+        return None;
+    }
+    let (next, found) = (events.next, remarks.len());
+    find_nested_remarks(origin, call, true, indices, events, remarks);
+    if let Some(event) = events.next_if(|event| is_result(event) && event.is_ok()) {
+        return Some(event);
+    }
+    events.next = next;
+    for remark in &mut remarks[found..] {
+        remark.executed = false;
+    }
+    events.next_if(is_result)
+}
+
+/// Derive the account id of a multisig in the same way as pallet-multisig. All the signatories must have account ids of the same length.
+fn multi_account_id(mut signatories: Vec<Vec<u8>>, threshold: u16) -> Option<Vec<u8>> {
+    signatories.sort();
+    let len = signatories.first()?.len();
+    if signatories.iter().any(|account_id| account_id.len() != len) {
+        return None;
+    }
+    // Encode the signatories as a vector of fixed length account ids.
+    let mut data = b"modlpy/utilisuba".to_vec();
+    Compact(u32::try_from(signatories.len()).ok()?).encode_to(&mut data);
+    data.extend(signatories.concat());
+    threshold.encode_to(&mut data);
+    // Account ids shorter than the hash are taken from the start of it.
+    Some(BlakeTwo256.hash(&data).0[..len].to_vec())
+}
+
+/// Read an account id from a decoded `MultiAddress` or plain account id, recording any index that needs to be looked up.
+fn multi_address_account_id(value: &Value<u32>, indices: &mut AccountIndices) -> Option<Vec<u8>> {
+    // Older runtimes use a plain account id.
+    let ValueDef::Variant(address) = &value.value else {
+        return account_id_bytes(value);
+    };
+    let inner = address.values.values().next()?;
+    match address.name.as_str() {
+        "Id" | "Address32" | "Address20" => account_id_bytes(inner),
+        "Raw" => value_bytes(inner).filter(|raw| matches!(raw.len(), 20 | 32)),
+        "Index" => indices.get(inner.as_u128()?.try_into().ok()?),
+        _ => None,
+    }
+}
+
+/// Read the bytes of a decoded 32 byte or Ethereum-style 20 byte account id.
+fn account_id_bytes(value: &Value<u32>) -> Option<Vec<u8>> {
+    let ValueDef::Composite(composite) = &value.value else {
+        return None;
+    };
+    // An AccountId32 is a newtype around a byte array.
+    if composite.len() == 1 {
+        return account_id_bytes(composite.values().next()?);
     }
+    let bytes: Vec<u8> = composite
+        .values()
+        .map(|byte| byte.as_u128().and_then(|byte| byte.try_into().ok()))
+        .collect::<Option<_>>()?;
+    matches!(bytes.len(), 20 | 32).then_some(bytes)
+}
+
+/// Read the bytes of a decoded byte array or vector, such as a remark.
+fn value_bytes(value: &Value<u32>) -> Option<Vec<u8>> {
+    let ValueDef::Composite(bytes) = &value.value else {
+        return None;
+    };
+    bytes
+        .values()
+        .map(|byte| byte.as_u128().and_then(|byte| byte.try_into().ok()))
+        .collect()
 }
 
 pub fn load_spans(span_db: &Tree) -> Result<Vec<Span>, IndexError> {
@@ -141,9 +1046,14 @@ pub fn check_span(
     spans: &mut Vec<Span>,
     current_span: &mut Span,
 ) -> Result<(), IndexError> {
-    while let Some(span) = spans.last() {
+    // Find the span below the current span.
+    while let Some(i) = spans
+        .iter()
+        .rposition(|span| span.start < current_span.start)
+    {
+        let span = &spans[i];
         // Have we indexed all the blocks after the span?
-        if current_span.start > span.start && current_span.start - 1 <= span.end {
+        if current_span.start - 1 <= span.end {
             let skipped = span.end - span.start + 1;
             info!(
                 "📚 Skipping {} blocks from #{} to #{}",
@@ -151,10 +1061,13 @@ pub fn check_span(
                 span.start.to_formatted_string(&Locale::en),
                 span.end.to_formatted_string(&Locale::en),
             );
+            // Merge the span into the current span.
+            let mut span_writes = sled::Batch::default();
+            span_writes.remove(&span.end.to_be_bytes());
             current_span.start = span.start;
-            // Remove the span.
-            span_db.remove(span.end.to_be_bytes())?;
-            spans.pop();
+            write_span(&mut span_writes, current_span);
+            span_db.apply_batch(span_writes)?;
+            spans.remove(i);
         } else {
             break;
         }
@@ -162,54 +1075,476 @@ pub fn check_span(
     Ok(())
 }
 
-pub fn check_next_batch_block(spans: &[Span], next_batch_block: &mut u32) {
-    // Figure out the next block to index, skipping the next span if we have reached it.
-    let mut i = spans.len();
-    while i != 0 {
-        i -= 1;
-        if *next_batch_block >= spans[i].start && *next_batch_block <= spans[i].end {
-            *next_batch_block = spans[i].start - 1;
+pub fn check_next_batch_block(
+    spans: &[Span],
+    poisoned: &Tree,
+    next_batch_block: &mut Option<u32>,
+    from_block: u32,
+) -> Result<(), IndexError> {
+    // Skipping poisoned blocks can reach a span, and skipping a span can reach poisoned blocks, so repeat until neither moves the block.
+    loop {
+        let block_number = *next_batch_block;
+        // Figure out the next block to index, skipping the next span if we have reached it.
+        let mut i = spans.len();
+        while i != 0 {
+            i -= 1;
+            if let Some(block_number) = *next_batch_block
+                && block_number >= spans[i].start
+                && block_number <= spans[i].end
+            {
+                *next_batch_block = spans[i].start.checked_sub(1);
+            }
+        }
+        // Skip blocks that have failed too many times.
+        while let Some(block_number) = *next_batch_block
+            && poisoned.contains_key(block_number.to_be_bytes())?
+        {
+            *next_batch_block = block_number.checked_sub(1);
+        }
+        if *next_batch_block == block_number {
+            break;
         }
     }
+    // Stop at the lowest block to index.
+    if next_batch_block.is_some_and(|block_number| block_number < from_block) {
+        *next_batch_block = None;
+    }
+    Ok(())
 }
 
-pub async fn substrate_index(
-    trees: Trees,
-    api: OnlineClient<PolkadotConfig>,
-    rpc: LegacyRpcMethods<PolkadotConfig>,
+/// Connect to a node.
+async fn connect<C: IndexerConfig>(url: &str) -> Result<(OnlineClient<C>, RpcClient), IndexError> {
+    let rpc_client = RpcClient::from_url(url).await?;
+    let api = OnlineClient::<C>::from_rpc_client(rpc_client.clone()).await?;
+    Ok((api, rpc_client))
+}
+
+/// Subscribe to new head blocks.
+async fn subscribe_head<C: IndexerConfig>(
+    api: &OnlineClient<C>,
     best: bool,
-    queue_depth: u8,
+) -> Result<BlockStream<C>, IndexError> {
+    Ok(if best {
+        api.blocks().subscribe_best().await
+    } else {
+        api.blocks().subscribe_finalized().await
+    }?)
+}
+
+/// Add a span to a batch of span changes, unless it is empty.
+fn write_span(span_writes: &mut sled::Batch, span: &Span) {
+    if span.start <= span.end {
+        let value = SpanDbValue {
+            start: span.start.into(),
+        };
+        span_writes.insert(&span.end.to_be_bytes(), value.as_bytes());
+    }
+}
+
+/// Move the end of a span, adding the change to a batch of span changes.
+fn move_span_end(span_writes: &mut sled::Batch, span: &mut Span, end: u32) {
+    if span.start <= span.end {
+        span_writes.remove(&span.end.to_be_bytes());
+    }
+    span.end = end;
+    write_span(span_writes, span);
+}
+
+/// Parse a remark posted with a key. Replies, edits and deletions must be of an earlier feather, so threads can't have cycles and a feather can't be revised before it is posted.
+fn check_remark(remark: &[u8], key: &FeatherDbKey) -> Result<Option<FeatherRemark>, RemarkError> {
+    let feather = FeatherRemark::parse(remark)?;
+    let position = (key.block_number.get(), key.index.get());
+    match feather.as_ref().map(|feather| &feather.op) {
+        Some(FeatherOp::Reply(parent, _)) if (parent.block_number, parent.index) >= position => {
+            Err(RemarkError::ReplyToLater)
+        }
+        Some(FeatherOp::Edit(target, _) | FeatherOp::Delete(target))
+            if (target.block_number, target.index) >= position =>
+        {
+            Err(RemarkError::RevisionOfLater)
+        }
+        _ => Ok(feather),
+    }
+}
+
+/// Add the writes for a feather operation posted by an author with a key.
+fn write_feather(
+    writes: &mut BlockWrites,
+    key: FeatherDbKey,
+    author: &[u8],
+    feather: FeatherRemark,
+    remark: &[u8],
+) {
+    let full_key = [key.as_bytes(), author].concat();
+    let value = FeatherDbValue::encode(feather.version, remark);
+    match feather.op {
+        FeatherOp::Post(_) => writes.feather.insert(full_key, value),
+        FeatherOp::Reply(parent, _) => {
+            let mut reply_key = ReplyDbKey {
+                block_number: parent.block_number.into(),
+                index: parent.index.into(),
+                reply: key.clone(),
+            }
+            .as_bytes()
+            .to_vec();
+            reply_key.extend_from_slice(author);
+            writes.reply.insert(reply_key, &[]);
+            writes.feather.insert(full_key, value);
+        }
+        // Revisions are indexed by the feather they revise. Whether the author is the same is checked when they are read, as the feather may not be indexed yet.
+        FeatherOp::Edit(target, _) | FeatherOp::Delete(target) => {
+            let mut index_key = RevisionIndexDbKey {
+                block_number: target.block_number.into(),
+                index: target.index.into(),
+                revision: key.clone(),
+            }
+            .as_bytes()
+            .to_vec();
+            index_key.extend_from_slice(author);
+            writes.revision_index.insert(index_key, &[]);
+            let mut revision = RevisionDbValue {
+                block_number: target.block_number.into(),
+                index: target.index.into(),
+            }
+            .as_bytes()
+            .to_vec();
+            revision.extend_from_slice(&value);
+            writes.revision.insert(full_key, revision);
+        }
+        // Parts are indexed by their set, to form a feather once they are all posted.
+        FeatherOp::Part(part) => {
+            let mut set_key = PartSetDbKey::prefix(author, part.id.as_bytes());
+            set_key.extend_from_slice(
+                PartSetDbKey {
+                    part: part.part.into(),
+                    position: key,
+                }
+                .as_bytes(),
+            );
+            let value = PartDbValue::encode(part.part, part.count, &part.id, &part.data);
+            writes.part_set.insert(set_key.as_slice(), &[]);
+            writes.part.insert(full_key, value.as_slice());
+            writes.parts.push((set_key, value));
+        }
+    }
+}
+
+/// Form feathers from sets of parts that are completed by the blocks being committed.
+fn assemble_parts(
+    trees: &Trees,
+    part_window: u32,
+    blocks: &[BlockWrites],
+) -> Result<BlockWrites, IndexError> {
+    let mut writes = BlockWrites::default();
+    let retracted: AHashSet<&[u8]> = blocks
+        .iter()
+        .flat_map(|block| block.retracted_parts.iter().map(Vec::as_slice))
+        .collect();
+    let suffix_len = size_of::<PartSetDbKey>();
+    let mut prefixes: Vec<&[u8]> = vec![];
+    for (set_key, _) in blocks.iter().flat_map(|block| &block.parts) {
+        let prefix = &set_key[..set_key.len() - suffix_len];
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
+    for prefix in prefixes {
+        // Parts of the set that have been committed, and parts that are being committed.
+        let mut parts = BTreeMap::new();
+        for (set_key, _) in trees.part_set.scan_prefix(prefix).flatten() {
+            if retracted.contains(set_key.as_ref()) {
+                continue;
+            }
+            let Some((author, _, part)) = PartSetDbKey::decode(&set_key) else {
+                continue;
+            };
+            if let Some(value) = trees
+                .part
+                .get([part.position.as_bytes(), author].concat())?
+            {
+                parts.insert(set_key.to_vec(), value.to_vec());
+            }
+        }
+        let mut committing = AHashSet::new();
+        for (set_key, value) in blocks.iter().flat_map(|block| &block.parts) {
+            if set_key.starts_with(prefix) {
+                parts.insert(set_key.clone(), value.clone());
+                committing.insert(&set_key[set_key.len() - size_of::<FeatherDbKey>()..]);
+            }
+        }
+        let set_parts: Vec<(PartSetDbKey, Vec<u8>)> = parts
+            .iter()
+            .filter_map(|(set_key, value)| {
+                let (_, _, part) = PartSetDbKey::decode(set_key)?;
+                Some((part, value.clone()))
+            })
+            .collect();
+        // A set forms one feather, from its earliest complete attempt, whatever order its parts are committed in. If an attempt was complete without the parts being committed, the set has formed its feather already.
+        let complete = PartSet::complete(&set_parts, part_window);
+        let is_committing = |set: &PartSet| {
+            set.parts
+                .iter()
+                .any(|(_, key, _)| committing.contains(key.as_bytes()))
+        };
+        if !complete.iter().all(is_committing) {
+            continue;
+        }
+        let Some(set) = complete.into_iter().next() else {
+            continue;
+        };
+        let Some((author, id, _)) = parts
+            .keys()
+            .next()
+            .and_then(|set_key| PartSetDbKey::decode(set_key))
+        else {
+            continue;
+        };
+        // The feather is posted with the key of the last part.
+        let key = set
+            .parts
+            .iter()
+            .map(|(_, key, _)| key)
+            .max_by_key(|key| key.as_bytes())
+            .unwrap()
+            .clone();
+        let remark: Vec<u8> = set
+            .parts
+            .iter()
+            .flat_map(|(_, _, data)| data.iter().copied())
+            .collect();
+        let block_number = key.block_number.get();
+        match check_remark(&remark, &key) {
+            Ok(Some(feather)) if !matches!(feather.op, FeatherOp::Part(_)) => {
+                info!(
+                    "🧩 #{}: feather formed from {} parts of {:#?}",
+                    block_number.to_formatted_string(&Locale::en),
+                    set.count,
+                    String::from_utf8_lossy(id),
+                );
+                write_feather(&mut writes, key, author, feather, &remark);
+            }
+            result => {
+                let reason = result.err().unwrap_or(RemarkError::InvalidParts);
+                warn!(
+                    "#{}: parts of {:#?} don't form a feather: {}",
+                    block_number.to_formatted_string(&Locale::en),
+                    String::from_utf8_lossy(id),
+                    reason
+                );
+                writes.rejected.insert(
+                    [key.as_bytes(), author].concat(),
+                    RejectedDbValue::encode(reason, &remark),
+                );
+            }
+        }
+    }
+    Ok(writes)
+}
+
+/// Commit the writes for indexed blocks in one transaction with the span changes that cover them, so a crash can't leave feathers without coverage or coverage without feathers.
+fn commit_blocks(
+    trees: &Trees,
+    part_window: u32,
+    blocks: &[BlockWrites],
+    span_writes: &sled::Batch,
+) -> Result<(), IndexError> {
+    commit_blocks_with(trees, part_window, blocks, span_writes, || Ok(()))
+}
+
+/// Commit the writes for indexed blocks like `commit_blocks`, calling `finish` after all the writes are made. The transaction is aborted if `finish` fails.
+fn commit_blocks_with(
+    trees: &Trees,
+    part_window: u32,
+    blocks: &[BlockWrites],
+    span_writes: &sled::Batch,
+    finish: impl Fn() -> Result<(), ConflictableTransactionError>,
+) -> Result<(), IndexError> {
+    let assembled = assemble_parts(trees, part_window, blocks)?;
+    (
+        &trees.feather,
+        &trees.extrinsic,
+        &trees.failed,
+        &trees.rejected,
+        &trees.revision,
+        &trees.revision_index,
+        &trees.reply,
+        &trees.part,
+        &trees.part_set,
+        &trees.timestamp,
+        &trees.block_hash,
+        &trees.span,
+    )
+        .transaction(
+            |(
+                feather,
+                extrinsic,
+                failed,
+                rejected,
+                revision,
+                revision_index,
+                reply,
+                part,
+                part_set,
+                timestamp,
+                block_hash,
+                span,
+            )| {
+                for writes in blocks.iter().chain([&assembled]) {
+                    feather.apply_batch(&writes.feather)?;
+                    extrinsic.apply_batch(&writes.extrinsic)?;
+                    failed.apply_batch(&writes.failed)?;
+                    rejected.apply_batch(&writes.rejected)?;
+                    revision.apply_batch(&writes.revision)?;
+                    revision_index.apply_batch(&writes.revision_index)?;
+                    reply.apply_batch(&writes.reply)?;
+                    part.apply_batch(&writes.part)?;
+                    part_set.apply_batch(&writes.part_set)?;
+                    timestamp.apply_batch(&writes.timestamp)?;
+                    block_hash.apply_batch(&writes.block_hash)?;
+                }
+                span.apply_batch(span_writes)?;
+                finish()
+            },
+        )?;
+    Ok(())
+}
+
+/// Check that a node is for the chain the database was created for. On first run, bind the database to the chain of the node.
+async fn check_chain<C: IndexerConfig>(
+    trees: &Trees,
+    api: &OnlineClient<C>,
+    rpc_client: &RpcClient,
+    rebind_chain: bool,
+) -> Result<(), IndexError> {
+    let genesis_hash = api.genesis_hash();
+    let chain_name = LegacyRpcMethods::<C>::new(rpc_client.clone())
+        .system_chain()
+        .await?;
+    if let Some(stored_hash) = trees.metadata.get(b"genesis_hash")?
+        && stored_hash.as_ref() != genesis_hash.as_bytes()
+    {
+        let stored_name = trees.metadata.get(b"chain_name")?.unwrap_or_default();
+        let stored_name = String::from_utf8_lossy(&stored_name);
+        if !rebind_chain {
+            error!(
+                "Database is for chain {} with genesis hash 0x{}, but the node is for chain {} with genesis hash {:?}. Pass --rebind-chain to index it anyway.",
+                stored_name,
+                hex::encode(stored_hash),
+                chain_name,
+                genesis_hash,
+            );
+            return Err(IndexError::WrongChain);
+        }
+        warn!(
+            "Rebinding database from chain {} to chain {}.",
+            stored_name, chain_name
+        );
+    }
+    let mut batch = sled::Batch::default();
+    batch.insert(b"genesis_hash", genesis_hash.as_bytes());
+    batch.insert(b"chain_name", chain_name.as_bytes());
+    trees.metadata.apply_batch(batch)?;
+    info!("⛓️  Chain: {} {:?}", chain_name, genesis_hash);
+    Ok(())
+}
+
+pub async fn substrate_index<C: IndexerConfig>(
+    trees: Trees,
+    urls: Vec<String>,
+    settings: ChainSettings,
+    status_tx: watch::Sender<IndexerStatus>,
     mut exit_rx: watch::Receiver<bool>,
 ) -> Result<(), IndexError> {
     info!(
         "📇 Load feathers before finalization: {}",
-        match best {
+        match settings.best {
             false => "disabled",
             true => "enabled",
         },
     );
 
-    let mut blocks_sub = if best {
-        api.blocks().subscribe_best().await
-    } else {
-        api.blocks().subscribe_finalized().await
-    }?;
+    let mut url_index = 0;
+    let mut failures = 0;
+
+    loop {
+        let url = &urls[url_index];
+        info!("Connecting to: {}", url);
+        match connect::<C>(url).await {
+            Ok((api, rpc_client)) => {
+                failures = 0;
+                let result =
+                    match check_chain(&trees, &api, &rpc_client, settings.rebind_chain).await {
+                        Ok(()) => {
+                            index_connection(
+                                &trees,
+                                api,
+                                rpc_client,
+                                &settings,
+                                &status_tx,
+                                &mut exit_rx,
+                            )
+                            .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                match result {
+                    Ok(()) => return Ok(()),
+                    Err(
+                        err @ (IndexError::Sled(_)
+                        | IndexError::Transaction(_)
+                        | IndexError::WrongChain),
+                    ) => {
+                        return Err(err);
+                    }
+                    Err(err) => error!("Connection to {} lost: {:?}", url, err),
+                }
+            }
+            Err(err) => {
+                error!("Failed to connect to {}: {:?}", url, err);
+                failures += 1;
+            }
+        }
+        // Try the next node after waiting, backing off while connections keep failing.
+        url_index = (url_index + 1) % urls.len();
+        let delay = RECONNECT_DELAY * 2u32.pow(failures.min(6));
+        info!("Reconnecting in {} seconds.", delay.as_secs());
+        tokio::select! {
+            _ = exit_rx.changed() => return Ok(()),
+            _ = time::sleep(delay) => {}
+        }
+    }
+}
+
+async fn index_connection<C: IndexerConfig>(
+    trees: &Trees,
+    api: OnlineClient<C>,
+    rpc_client: RpcClient,
+    settings: &ChainSettings,
+    status_tx: &watch::Sender<IndexerStatus>,
+    exit_rx: &mut watch::Receiver<bool>,
+) -> Result<(), IndexError> {
+    let ChainSettings {
+        best,
+        queue_depth,
+        from_block,
+        to_block,
+        part_window,
+        ..
+    } = *settings;
+    let mut blocks_sub = subscribe_head(&api, best).await?;
 
     // Determine the correct block to start batch indexing.
-    let mut next_batch_block: u32 = blocks_sub
+    let head: u32 = blocks_sub
         .next()
         .await
         .ok_or(IndexError::BlockNotFound(0))??
         .number();
-    info!(
-        "📚 Indexing backwards from #{}",
-        next_batch_block.to_formatted_string(&Locale::en)
-    );
+    let mut next_batch_block = Some(head);
     // Load already indexed spans from the db.
     let mut spans = load_spans(&trees.span)?;
     // If the first head block to be indexed will be touching the last span (the indexer was restarted), set the current span to the last span. Otherwise there will be no batch block indexed to connect the current span to the last span.
     let mut current_span = if let Some(span) = spans.last()
-        && span.end == next_batch_block
+        && span.end == head
     {
         let span = span.clone();
         let skipped = span.end - span.start + 1;
@@ -219,36 +1554,74 @@ pub async fn substrate_index(
             span.start.to_formatted_string(&Locale::en),
             span.end.to_formatted_string(&Locale::en),
         );
-        // Remove the span.
-        trees.span.remove(span.end.to_be_bytes())?;
         spans.pop();
-        next_batch_block = span.start - 1;
+        next_batch_block = span.start.checked_sub(1);
         span
     } else {
         Span {
-            start: next_batch_block + 1,
-            end: next_batch_block + 1,
+            start: head + 1,
+            end: head,
         }
     };
+    // Blocks above the upper bound are only indexed by following the head, so batch indexing needs its own span.
+    let mut batch_span = None;
+    if let Some(to_block) = to_block
+        && next_batch_block.is_some_and(|block_number| block_number > to_block)
+    {
+        let span = match spans
+            .iter()
+            .position(|span| span.start <= to_block && span.end >= to_block)
+        {
+            Some(i) => spans.remove(i),
+            None => Span {
+                start: to_block + 1,
+                end: to_block,
+            },
+        };
+        next_batch_block = span.start.checked_sub(1);
+        batch_span = Some(span);
+    }
+    if let Some(block_number) = next_batch_block {
+        info!(
+            "📚 Indexing backwards from #{} to #{}",
+            block_number.to_formatted_string(&Locale::en),
+            from_block.to_formatted_string(&Locale::en)
+        );
+    }
 
-    let indexer = Indexer::new(trees.clone(), api, rpc);
+    let indexer = Indexer::new(trees.clone(), api.clone(), rpc_client, best);
 
-    let mut head_future = Box::pin(indexer.index_head(blocks_sub.next()));
+    // First block that has not been indexed by following the head.
+    let mut next_head_block = head + 1;
+    let mut head_future = Box::pin(indexer.index_head(blocks_sub.next(), next_head_block));
+    // Number of consecutive times the first unindexed head block has failed.
+    let mut head_attempts = 0;
 
     info!("📚 Queue depth: {}", queue_depth);
-    let mut futures = Vec::with_capacity(queue_depth.try_into().unwrap());
+    let mut futures = FuturesUnordered::new();
 
     for _ in 0..queue_depth {
-        check_next_batch_block(&spans, &mut next_batch_block);
-        futures.push(Box::pin(indexer.index_block(next_batch_block)));
+        check_next_batch_block(&spans, &trees.poisoned, &mut next_batch_block, from_block)?;
+        let Some(block_number) = next_batch_block else {
+            break;
+        };
+        futures.push(indexer.index_batch_block(block_number, Duration::ZERO));
         debug!(
             "⬆️  Block #{} queued.",
-            next_batch_block.to_formatted_string(&Locale::en)
+            block_number.to_formatted_string(&Locale::en)
         );
-        next_batch_block -= 1;
+        next_batch_block = block_number.checked_sub(1);
+    }
+
+    let mut is_batching = !futures.is_empty();
+    if !is_batching {
+        info!("📚 Backfill complete.");
+        status_tx.send_modify(|status| status.backfill_complete = true);
     }
 
     let mut orphans: AHashMap<u32, ()> = AHashMap::new();
+    // Number of times each failing batch block has been attempted.
+    let mut failures: AHashMap<u32, u32> = AHashMap::new();
 
     let mut stats_block_count = 0;
     let mut stats_feather_count = 0;
@@ -258,111 +1631,724 @@ pub async fn substrate_index(
     let mut interval = time::interval_at(Instant::now() + interval_duration, interval_duration);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    let mut is_batching = true;
-
     loop {
         tokio::select! {
             biased;
 
             _ = exit_rx.changed() => {
-                if current_span.start != current_span.end {
-                    let value = SpanDbValue {
-                        start: current_span.start.into(),
-                    };
-                    trees.span.insert(current_span.end.to_be_bytes(), value.as_bytes())?;
-                    info!(
-                        "📚 Recording current indexed span from #{} to #{}",
-                        current_span.start.to_formatted_string(&Locale::en),
-                        current_span.end.to_formatted_string(&Locale::en)
-                    );
-                }
                 return Ok(());
             }
             result = &mut head_future => {
+                drop(head_future);
                 match result {
-                    Ok((block_number, feather_count)) => {
-                        trees.span.remove(current_span.end.to_be_bytes())?;
-                        current_span.end = block_number;
-                        let value = SpanDbValue {
-                            start: current_span.start.into(),
-                        };
-                        trees.span.insert(current_span.end.to_be_bytes(), value.as_bytes())?;
+                    Ok((block_number, feather_count, blocks)) => {
+                        let mut span_writes = sled::Batch::default();
+                        move_span_end(&mut span_writes, &mut current_span, block_number);
+                        commit_blocks(trees, part_window, &blocks, &span_writes)?;
                         info!(
                             "✨ #{}: {} feathers",
                             block_number.to_formatted_string(&Locale::en),
                             feather_count.to_formatted_string(&Locale::en),
                         );
-                        drop(head_future);
-                        head_future = Box::pin(indexer.index_head(blocks_sub.next()));
+                        next_head_block = block_number + 1;
+                        head_attempts = 0;
                     },
-                    Err(error) => {
-                        match error {
-                            IndexError::BlockNotFound(block_number) => {
-                                error!("✨ Block not found #{}", block_number.to_formatted_string(&Locale::en));
-                            },
-                            err => {
-                                error!("✨ Indexing failed: {}", err);
-                            },
+                    Err((_, error, _)) if error.is_disconnected() => {
+                        return Err(error);
+                    },
+                    Err((_, IndexError::SubscriptionEnded, _)) => {
+                        warn!("✨ Head subscription ended, resubscribing.");
+                        blocks_sub = subscribe_head(&api, best).await?;
+                    },
+                    Err((None, error, _)) => {
+                        status_tx.send_modify(|status| status.head_failures += 1);
+                        error!("✨ Indexing failed: {:?}", error);
+                    },
+                    Err((Some(block_number), error, blocks)) => {
+                        status_tx.send_modify(|status| status.head_failures += 1);
+                        // Everything below the failed block has been indexed.
+                        let mut span_writes = sled::Batch::default();
+                        if block_number > current_span.start {
+                            move_span_end(&mut span_writes, &mut current_span, block_number - 1);
+                        }
+                        commit_blocks(trees, part_window, &blocks, &span_writes)?;
+                        if block_number == next_head_block {
+                            head_attempts += 1;
+                        }
+                        else {
+                            next_head_block = block_number;
+                            head_attempts = 1;
+                        }
+                        if head_attempts < MAX_HEAD_ATTEMPTS {
+                            warn!(
+                                "✨ #{}: indexing failed, retrying with the next head: {:?}",
+                                block_number.to_formatted_string(&Locale::en),
+                                error,
+                            );
+                        }
+                        else {
+                            error!(
+                                "✨ #{}: indexing failed {} times, skipping: {:?}",
+                                block_number.to_formatted_string(&Locale::en),
+                                head_attempts,
+                                error,
+                            );
+                            trees.poisoned.insert(block_number.to_be_bytes(), format!("{:?}", error).as_bytes())?;
+                            // Continue above the poisoned block in a new span. Batch indexing continues below it.
+                            if batch_span.is_none() {
+                                batch_span = Some(Span {
+                                    start: current_span.start.min(block_number),
+                                    end: block_number - 1,
+                                });
+                            }
+                            current_span = Span {
+                                start: block_number + 1,
+                                end: block_number,
+                            };
+                            next_head_block = block_number + 1;
+                            head_attempts = 0;
                         }
                     },
                 };
+                head_future = Box::pin(indexer.index_head(blocks_sub.next(), next_head_block));
             }
             _ = interval.tick(), if is_batching => {
                 let current_time = Instant::now();
                 let duration = (current_time.duration_since(stats_start_time)).as_micros();
-                if duration != 0 {
+                if let Some(block_rate) = (<u32 as Into<u128>>::into(stats_block_count) * 1_000_000).checked_div(duration)
+                    && let Some(feather_rate) = (<u32 as Into<u128>>::into(stats_feather_count) * 1_000_000).checked_div(duration)
+                {
                     info!(
                         "📚 #{}: {} blocks/sec, {} feathers/sec",
-                        current_span.start.to_formatted_string(&Locale::en),
-                        (<u32 as Into<u128>>::into(stats_block_count) * 1_000_000 / duration).to_formatted_string(&Locale::en),
-                        (<u32 as Into<u128>>::into(stats_feather_count) * 1_000_000 / duration).to_formatted_string(&Locale::en),
+                        batch_span.as_ref().unwrap_or(&current_span).start.to_formatted_string(&Locale::en),
+                        block_rate.to_formatted_string(&Locale::en),
+                        feather_rate.to_formatted_string(&Locale::en),
                     );
                 }
                 stats_block_count = 0;
                 stats_feather_count = 0;
                 stats_start_time = current_time;
             }
-            (result, index, _) = future::select_all(&mut futures), if is_batching => {
+            Some((block_number, result)) = futures.next(), if is_batching => {
+                let mut queue_next = true;
                 match result {
-                    Ok((block_number, feather_count)) => {
-                        // Is the new block contiguous to the current span or an orphan?
-                        if block_number == current_span.start - 1 {
-                            current_span.start = block_number;
+                    Ok((feather_count, writes)) => {
+                        failures.remove(&block_number);
+                        let span = batch_span.as_mut().unwrap_or(&mut current_span);
+                        let mut span_writes = sled::Batch::default();
+                        // Is the new block contiguous to the batch span or an orphan?
+                        if span.start.checked_sub(1) == Some(block_number) {
+                            span.start = block_number;
+                            write_span(&mut span_writes, span);
+                            commit_blocks(trees, part_window, &[writes], &span_writes)?;
                             debug!("⬇️  Block #{} indexed.", block_number.to_formatted_string(&Locale::en));
-                            check_span(&trees.span, &mut spans, &mut current_span)?;
-                            // Check if any orphans are now contiguous.
-                            while orphans.contains_key(&(current_span.start - 1)) {
-                                current_span.start -= 1;
-                                orphans.remove(&current_span.start);
-                                debug!("➡️  Block #{} unorphaned.", current_span.start.to_formatted_string(&Locale::en));
-                                check_span(&trees.span, &mut spans, &mut current_span)?;
-                            }
+                            check_span(&trees.span, &mut spans, span)?;
                         }
+                        // Orphans are recorded as their own single-block spans until they become contiguous.
                         else {
+                            write_span(&mut span_writes, &Span { start: block_number, end: block_number });
+                            commit_blocks(trees, part_window, &[writes], &span_writes)?;
                             orphans.insert(block_number, ());
                             debug!("⬇️  Block #{} indexed and orphaned.", block_number.to_formatted_string(&Locale::en));
                         }
                         stats_block_count += 1;
                         stats_feather_count += feather_count;
                     },
+                    // Don't count failures caused by losing the connection.
+                    Err(error) if error.is_disconnected() => {
+                        return Err(error);
+                    }
                     Err(error) => {
-                        match error {
-                            IndexError::BlockNotFound(block_number) => {
-                                error!("📚 Block not found #{}", block_number.to_formatted_string(&Locale::en));
-                                is_batching = false;
-                            },
-                            _ => {
-                                error!("📚 Batch indexing failed: {:?}", error);
-                                is_batching = false;
-                            },
+                        status_tx.send_modify(|status| status.batch_failures += 1);
+                        let attempts = failures.entry(block_number).or_insert(0);
+                        *attempts += 1;
+                        if *attempts < MAX_BATCH_ATTEMPTS {
+                            let delay = BATCH_RETRY_DELAY * 2u32.pow(*attempts - 1);
+                            warn!(
+                                "📚 #{}: indexing failed, retrying in {} seconds: {:?}",
+                                block_number.to_formatted_string(&Locale::en),
+                                delay.as_secs(),
+                                error,
+                            );
+                            futures.push(indexer.index_batch_block(block_number, delay));
+                            queue_next = false;
+                        }
+                        else {
+                            error!(
+                                "📚 #{}: indexing failed {} times, skipping: {:?}",
+                                block_number.to_formatted_string(&Locale::en),
+                                attempts,
+                                error,
+                            );
+                            failures.remove(&block_number);
+                            trees.poisoned.insert(block_number.to_be_bytes(), format!("{:?}", error).as_bytes())?;
                         }
                     }
                 }
-                check_next_batch_block(&spans, &mut next_batch_block);
-                futures[index] = Box::pin(indexer.index_block(next_batch_block));
-                debug!("⬆️  Block #{} queued.", next_batch_block.to_formatted_string(&Locale::en));
-                next_batch_block -= 1;
+                // Check if any orphans are now contiguous.
+                loop {
+                    let span = batch_span.as_mut().unwrap_or(&mut current_span);
+                    let Some(next_block) = span.start.checked_sub(1) else {
+                        break;
+                    };
+                    if orphans.remove(&next_block).is_some() {
+                        // Replace the orphan's own span.
+                        let mut span_writes = sled::Batch::default();
+                        span_writes.remove(&next_block.to_be_bytes());
+                        span.start = next_block;
+                        write_span(&mut span_writes, span);
+                        trees.span.apply_batch(span_writes)?;
+                        debug!("➡️  Block #{} unorphaned.", next_block.to_formatted_string(&Locale::en));
+                        check_span(&trees.span, &mut spans, span)?;
+                    }
+                    // Poisoned blocks have not been indexed, so continue below them in a new span.
+                    else if next_block != 0 && trees.poisoned.contains_key(next_block.to_be_bytes())? {
+                        batch_span = Some(Span {
+                            start: next_block,
+                            end: next_block - 1,
+                        });
+                    }
+                    else {
+                        break;
+                    }
+                }
+                if queue_next {
+                    check_next_batch_block(&spans, &trees.poisoned, &mut next_batch_block, from_block)?;
+                    if let Some(block_number) = next_batch_block {
+                        futures.push(indexer.index_batch_block(block_number, Duration::ZERO));
+                        debug!("⬆️  Block #{} queued.", block_number.to_formatted_string(&Locale::en));
+                        next_batch_block = block_number.checked_sub(1);
+                    }
+                }
+                if futures.is_empty() {
+                    info!("📚 Backfill complete.");
+                    status_tx.send_modify(|status| status.backfill_complete = true);
+                    is_batching = false;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Open the trees of a chain in a temporary database.
+    fn temporary_trees() -> Trees {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        crate::open_trees(&db, "test").unwrap()
+    }
+
+    /// Writes for a block with one feather, and the span change that covers the block.
+    fn block_writes(block_number: u32) -> (BlockWrites, sled::Batch) {
+        let mut writes = BlockWrites::default();
+        let remark = b"FEATHER::genre::title::content";
+        let feather = FeatherRemark::parse(remark).unwrap().unwrap();
+        let key = FeatherDbKey {
+            block_number: block_number.into(),
+            index: 1.into(),
+            sub_index: 0.into(),
+        };
+        write_feather(&mut writes, key, &[1; 32], feather, remark);
+        let key = ExtrinsicDbKey {
+            block_number: block_number.into(),
+            index: 1.into(),
+        };
+        writes.extrinsic.insert(key.as_bytes(), [0; 32].as_slice());
+        writes
+            .block_hash
+            .insert(&block_number.to_be_bytes(), [2; 32].as_slice());
+        let mut span_writes = sled::Batch::default();
+        write_span(
+            &mut span_writes,
+            &Span {
+                start: block_number,
+                end: block_number,
+            },
+        );
+        (writes, span_writes)
+    }
+
+    #[test]
+    fn aborted_commit_writes_nothing() {
+        let trees = temporary_trees();
+        let (writes, span_writes) = block_writes(10);
+        let result = commit_blocks_with(&trees, 600, &[writes], &span_writes, || {
+            Err(ConflictableTransactionError::Abort(
+                sled::Error::Unsupported("interrupted".into()),
+            ))
+        });
+        assert!(result.is_err());
+        assert!(trees.feather.is_empty());
+        assert!(trees.extrinsic.is_empty());
+        assert!(trees.block_hash.is_empty());
+        assert!(trees.span.is_empty());
+    }
+
+    #[test]
+    fn commit_writes_blocks_and_span() {
+        let trees = temporary_trees();
+        let (writes, span_writes) = block_writes(10);
+        commit_blocks(&trees, 600, &[writes], &span_writes).unwrap();
+        assert_eq!(trees.feather.len(), 1);
+        assert_eq!(trees.extrinsic.len(), 1);
+        assert!(trees.block_hash.contains_key(10u32.to_be_bytes()).unwrap());
+        assert_eq!(
+            load_spans(&trees.span).unwrap(),
+            [Span { start: 10, end: 10 }]
+        );
+    }
+
+    #[test]
+    fn aborted_commit_keeps_previous_span() {
+        let trees = temporary_trees();
+        let (writes, span_writes) = block_writes(10);
+        commit_blocks(&trees, 600, &[writes], &span_writes).unwrap();
+        // Extend the span to the next block, but abort before the transaction ends.
+        let (writes, _) = block_writes(11);
+        let mut span = Span { start: 10, end: 10 };
+        let mut span_writes = sled::Batch::default();
+        move_span_end(&mut span_writes, &mut span, 11);
+        let result = commit_blocks_with(&trees, 600, &[writes], &span_writes, || {
+            Err(ConflictableTransactionError::Abort(
+                sled::Error::Unsupported("interrupted".into()),
+            ))
+        });
+        assert!(result.is_err());
+        assert_eq!(trees.feather.len(), 1);
+        assert!(!trees.block_hash.contains_key(11u32.to_be_bytes()).unwrap());
+        assert_eq!(
+            load_spans(&trees.span).unwrap(),
+            [Span { start: 10, end: 10 }]
+        );
+    }
+
+    /// Writes for a block with a part of a set of two, and the span change that covers the block.
+    fn part_writes(block_number: u32, part: u16, data: &str) -> (BlockWrites, sled::Batch) {
+        let mut writes = BlockWrites::default();
+        let remark = format!("FEATHER:2::part::set::{part}::2::{data}");
+        let feather = FeatherRemark::parse(remark.as_bytes()).unwrap().unwrap();
+        let key = FeatherDbKey {
+            block_number: block_number.into(),
+            index: 1.into(),
+            sub_index: 0.into(),
+        };
+        write_feather(&mut writes, key, &[1; 32], feather, remark.as_bytes());
+        let mut span_writes = sled::Batch::default();
+        write_span(
+            &mut span_writes,
+            &Span {
+                start: block_number,
+                end: block_number,
+            },
+        );
+        (writes, span_writes)
+    }
+
+    /// Commit parts one block at a time, and return the block numbers of the feathers formed.
+    fn commit_parts(parts: &[(u32, u16, &str)]) -> Vec<u32> {
+        let trees = temporary_trees();
+        for (block_number, part, data) in parts {
+            let (writes, span_writes) = part_writes(*block_number, *part, data);
+            commit_blocks(&trees, 600, &[writes], &span_writes).unwrap();
+        }
+        assert!(trees.rejected.is_empty());
+        trees
+            .feather
+            .iter()
+            .keys()
+            .map(|key| {
+                let (key, _) = FeatherDbKey::read_from_prefix(&key.unwrap()).unwrap();
+                key.block_number.get()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parts_form_the_same_feather_in_any_order() {
+        let first = (100, 1, "FEATHER\\:\\:genre\\:\\:");
+        let second = (101, 2, "title\\:\\:content");
+        let again = (150, 2, "title\\:\\:again");
+        // Following the head
+        assert_eq!(commit_parts(&[first, second, again]), [101]);
+        // Backfilling
+        assert_eq!(commit_parts(&[again, second, first]), [101]);
+        // A feather that has formed is kept when a part of an earlier attempt is committed after it
+        assert_eq!(commit_parts(&[first, again, second]), [150]);
+    }
+
+    const ALICE: &str = "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d";
+    const BOB: &str = "8eaf04151687736326c9fea17e25fc5287613693c912909cb226aa4794f26a48";
+    const CHARLIE: &str = "90b5ab205c6974c9ea841be688864633dc9ca8a357843eeacf2314649965fe22";
+
+    fn account(hex_id: &str) -> Vec<u8> {
+        hex::decode(hex_id).unwrap()
+    }
+
+    /// A decoded `AccountId32`.
+    fn account_value(account_id: &[u8]) -> Value<()> {
+        Value::unnamed_composite([Value::from_bytes(account_id)])
+    }
+
+    /// A decoded runtime call that posts a remark.
+    fn remark_call(remark: &[u8]) -> Value<()> {
+        Value::unnamed_variant(
+            "System",
+            [Value::named_variant(
+                "remark",
+                [("remark", Value::from_bytes(remark))],
+            )],
+        )
+    }
+
+    /// Find the remarks of a call made by an origin in an extrinsic that succeeded, with the events of the extrinsic. Returns the origin and whether each remark was executed.
+    fn find(
+        origin: &[u8],
+        pallet: &str,
+        call: &str,
+        fields: Value<()>,
+        events: Vec<DispatchEvent>,
+    ) -> Vec<(Vec<u8>, bool)> {
+        let ValueDef::Composite(fields) = fields.map_context(|_| 0u32).value else {
+            panic!("fields are not a composite");
+        };
+        let mut indices = AccountIndices::default();
+        let mut events = DispatchEvents { events, next: 0 };
+        let mut remarks = vec![];
+        find_remarks(
+            origin.to_vec(),
+            pallet,
+            call,
+            &fields,
+            true,
+            &mut indices,
+            &mut events,
+            &mut remarks,
+        );
+        remarks
+            .into_iter()
+            .map(|remark| (remark.origin, remark.executed))
+            .collect()
+    }
+
+    fn proxy_fields(real: Value<()>) -> Value<()> {
+        Value::named_composite([
+            ("real", real),
+            ("force_proxy_type", Value::unnamed_variant("None", [])),
+            ("call", remark_call(b"FEATHER::x::y::z")),
+        ])
+    }
+
+    fn multisig_fields() -> Value<()> {
+        Value::named_composite([
+            ("threshold", Value::u128(2)),
+            (
+                "other_signatories",
+                Value::unnamed_composite([
+                    Value::from_bytes(account(BOB)),
+                    Value::from_bytes(account(CHARLIE)),
+                ]),
+            ),
+            ("maybe_timepoint", Value::unnamed_variant("None", [])),
+            ("call", remark_call(b"FEATHER::x::y::z")),
+        ])
+    }
+
+    #[test]
+    fn multisig_account_id() {
+        let multisig =
+            multi_account_id(vec![account(ALICE), account(BOB), account(CHARLIE)], 2).unwrap();
+        assert_eq!(
+            AccountId(multisig).to_string(),
+            "5DjYJStmdZ2rcqXbXGX7TW85JsrW6uG4y9MUcLq2BoPMpRA7"
+        );
+    }
+
+    #[test]
+    fn proxy_executed() {
+        let real = Value::unnamed_variant("Id", [account_value(&account(BOB))]);
+        assert_eq!(
+            find(
+                &account(ALICE),
+                "Proxy",
+                "proxy",
+                proxy_fields(real),
+                vec![DispatchEvent::ProxyExecuted(true)]
+            ),
+            [(account(BOB), true)]
+        );
+    }
+
+    #[test]
+    fn proxy_failed() {
+        let real = Value::unnamed_variant("Id", [account_value(&account(BOB))]);
+        assert_eq!(
+            find(
+                &account(ALICE),
+                "Proxy",
+                "proxy",
+                proxy_fields(real),
+                vec![DispatchEvent::ProxyExecuted(false)]
+            ),
+            [(account(BOB), false)]
+        );
+    }
+
+    #[test]
+    fn proxy_with_plain_account_id() {
+        assert_eq!(
+            find(
+                &account(ALICE),
+                "Proxy",
+                "proxy",
+                proxy_fields(account_value(&account(BOB))),
+                vec![DispatchEvent::ProxyExecuted(true)]
+            ),
+            [(account(BOB), true)]
+        );
+    }
+
+    #[test]
+    fn multisig_approval() {
+        let multisig =
+            multi_account_id(vec![account(ALICE), account(BOB), account(CHARLIE)], 2).unwrap();
+        assert_eq!(
+            find(
+                &account(ALICE),
+                "Multisig",
+                "as_multi",
+                multisig_fields(),
+                vec![DispatchEvent::MultisigApproval]
+            ),
+            [(multisig, false)]
+        );
+    }
+
+    #[test]
+    fn multisig_executed() {
+        let multisig =
+            multi_account_id(vec![account(ALICE), account(BOB), account(CHARLIE)], 2).unwrap();
+        assert_eq!(
+            find(
+                &account(ALICE),
+                "Multisig",
+                "as_multi",
+                multisig_fields(),
+                vec![DispatchEvent::MultisigExecuted(true)]
+            ),
+            [(multisig, true)]
+        );
+    }
+
+    fn batch_fields(calls: impl IntoIterator<Item = Value<()>>) -> Value<()> {
+        Value::named_composite([("calls", Value::unnamed_composite(calls))])
+    }
+
+    fn remark_calls(count: usize) -> Vec<Value<()>> {
+        (0..count)
+            .map(|_| remark_call(b"FEATHER::x::y::z"))
+            .collect()
+    }
+
+    fn proxy_call() -> Value<()> {
+        let real = Value::unnamed_variant("Id", [account_value(&account(BOB))]);
+        let ValueDef::Composite(fields) = proxy_fields(real).value else {
+            unreachable!();
+        };
+        Value::unnamed_variant("Proxy", [Value::variant("proxy", fields)])
+    }
+
+    #[test]
+    fn batch_interrupted() {
+        use DispatchEvent::*;
+        let alice = account(ALICE);
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "batch",
+                batch_fields(remark_calls(3)),
+                vec![ItemCompleted, BatchInterrupted(1)]
+            ),
+            [
+                (alice.clone(), true),
+                (alice.clone(), false),
+                (alice, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn force_batch_with_failed_item() {
+        use DispatchEvent::*;
+        let alice = account(ALICE);
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "force_batch",
+                batch_fields(remark_calls(3)),
+                vec![ItemCompleted, ItemFailed, ItemCompleted, BatchCompleted]
+            ),
+            [(alice.clone(), true), (alice.clone(), false), (alice, true)]
+        );
+    }
+
+    #[test]
+    fn proxy_in_batch() {
+        use DispatchEvent::*;
+        let alice = account(ALICE);
+        let calls = [remark_call(b"FEATHER::x::y::z"), proxy_call()];
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "batch",
+                batch_fields(calls.clone()),
+                vec![
+                    ItemCompleted,
+                    ProxyExecuted(true),
+                    ItemCompleted,
+                    BatchCompleted
+                ]
+            ),
+            [(alice.clone(), true), (account(BOB), true)]
+        );
+        // The proxied call failed, but the proxy call succeeded.
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "batch",
+                batch_fields(calls),
+                vec![
+                    ItemCompleted,
+                    ProxyExecuted(false),
+                    ItemCompleted,
+                    BatchCompleted
+                ]
+            ),
+            [(alice, true), (account(BOB), false)]
+        );
+    }
+
+    #[test]
+    fn failed_proxy_in_force_batch() {
+        use DispatchEvent::*;
+        let alice = account(ALICE);
+        let calls = [proxy_call(), remark_call(b"FEATHER::x::y::z")];
+        // The events of the failed proxy call were reverted.
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "force_batch",
+                batch_fields(calls),
+                vec![ItemFailed, ItemCompleted, BatchCompleted]
+            ),
+            [(account(BOB), false), (alice, true)]
+        );
+    }
+
+    #[test]
+    fn batch_without_item_events() {
+        use DispatchEvent::*;
+        let alice = account(ALICE);
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "batch",
+                batch_fields(remark_calls(3)),
+                vec![BatchInterrupted(2)]
+            ),
+            [
+                (alice.clone(), true),
+                (alice.clone(), true),
+                (alice.clone(), false)
+            ]
+        );
+        let calls = [proxy_call(), remark_call(b"FEATHER::x::y::z")];
+        assert_eq!(
+            find(
+                &alice,
+                "Utility",
+                "batch",
+                batch_fields(calls),
+                vec![ProxyExecuted(true), BatchCompleted]
+            ),
+            [(account(BOB), true), (alice, true)]
+        );
+    }
+
+    #[test]
+    fn next_batch_block_skips_spans_and_poisoned_blocks() {
+        let trees = temporary_trees();
+        let spans = [Span { start: 90, end: 99 }, Span { start: 50, end: 88 }];
+        trees.poisoned.insert(100u32.to_be_bytes(), b"").unwrap();
+        trees.poisoned.insert(89u32.to_be_bytes(), b"").unwrap();
+        let mut next_batch_block = Some(100);
+        check_next_batch_block(&spans, &trees.poisoned, &mut next_batch_block, 0).unwrap();
+        assert_eq!(next_batch_block, Some(49));
+        let mut next_batch_block = Some(100);
+        check_next_batch_block(&spans, &trees.poisoned, &mut next_batch_block, 50).unwrap();
+        assert_eq!(next_batch_block, None);
+    }
+
+    #[test]
+    fn decode_multi_address() {
+        use subxt::utils::{AccountId32, MultiAddress};
+        type Multi = MultiAddress<AccountId32, u32>;
+        let id = |address: Multi| match decode_address(&address.encode(), false) {
+            Some(Address::Id(account_id)) => Some(account_id),
+            _ => None,
+        };
+        assert_eq!(id(Multi::Id(AccountId32([5; 32]))), Some(vec![5; 32]));
+        assert_eq!(id(Multi::Address32([6; 32])), Some(vec![6; 32]));
+        assert_eq!(id(Multi::Address20([7; 20])), Some(vec![7; 20]));
+        assert_eq!(id(Multi::Raw(vec![8; 20])), Some(vec![8; 20]));
+        assert_eq!(id(Multi::Raw(vec![9; 32])), Some(vec![9; 32]));
+        // Raw addresses that encode to the length of a bare account id aren't mistaken for one.
+        assert_eq!(Multi::Raw(vec![1; 18]).encode().len(), 20);
+        assert!(decode_address(&Multi::Raw(vec![1; 18]).encode(), false).is_none());
+        assert_eq!(Multi::Raw(vec![1; 30]).encode().len(), 32);
+        assert!(decode_address(&Multi::Raw(vec![1; 30]).encode(), false).is_none());
+        assert!(matches!(
+            decode_address(&Multi::Index(70000).encode(), false),
+            Some(Address::Index(70000))
+        ));
+    }
+
+    #[test]
+    fn decode_truncated_multi_address() {
+        use subxt::utils::{AccountId32, MultiAddress};
+        type Multi = MultiAddress<AccountId32, u32>;
+        for address in [
+            Multi::Id(AccountId32([5; 32])),
+            Multi::Index(70000),
+            Multi::Raw(vec![8; 20]),
+            Multi::Address32([6; 32]),
+            Multi::Address20([7; 20]),
+        ] {
+            let bytes = address.encode();
+            assert!(decode_address(&bytes[..bytes.len() - 1], false).is_none());
+            assert!(decode_address(&[bytes.as_slice(), &[0]].concat(), false).is_none());
+        }
+        assert!(decode_address(&[], false).is_none());
+        assert!(decode_address(&[5], false).is_none());
+    }
+
+    #[test]
+    fn decode_bare_address() {
+        assert!(matches!(
+            decode_address(&[3; 20], true),
+            Some(Address::Id(account_id)) if account_id == [3; 20]
+        ));
+        assert!(matches!(
+            decode_address(&[3; 32], true),
+            Some(Address::Id(account_id)) if account_id == [3; 32]
+        ));
+        assert!(decode_address(&[3; 33], true).is_none());
+    }
+}
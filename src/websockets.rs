@@ -1,8 +1,7 @@
 use std::net::SocketAddr;
 
 use futures::{SinkExt, StreamExt};
-use sled::Tree;
-use subxt::utils::AccountId32;
+use subxt::utils::H256;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::watch::Receiver,
@@ -11,60 +10,92 @@ use tokio_tungstenite::tungstenite;
 use tracing_log::log::{debug, error, info};
 use zerocopy::{FromBytes, IntoBytes};
 
-use crate::Trees;
+use crate::remark::{FeatherOp, FeatherRemark};
 use crate::shared::*;
+use crate::{IndexedChain, Trees};
 
-pub fn process_msg_status(span_db: &Tree) -> ResponseMessage {
+pub fn process_msg_status(trees: &Trees, indexer_status: &IndexerStatus) -> ResponseMessage {
     let mut spans = vec![];
-    for (key, value) in span_db.into_iter().flatten() {
+    for (key, value) in trees.span.into_iter().flatten() {
         let span_value = SpanDbValue::read_from_bytes(&value).unwrap();
         let start: u32 = span_value.start.into();
         let end: u32 = u32::from_be_bytes(key.as_ref().try_into().unwrap());
         let span = Span { start, end };
         spans.push(span);
     }
-    ResponseMessage::Status(spans)
+    let mut poisoned = vec![];
+    for (key, _) in trees.poisoned.into_iter().flatten() {
+        poisoned.push(u32::from_be_bytes(key.as_ref().try_into().unwrap()));
+    }
+    ResponseMessage::Status(Status {
+        chain: trees.chain.clone(),
+        spans,
+        poisoned,
+        indexer: indexer_status.clone(),
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn process_msg_get_feathers(
-    feathers_db: &Tree,
+    trees: &Trees,
     block_number: u32,
     limit: u32,
-    account_id: Option<AccountId32>,
+    account_id: Option<AccountId>,
     genre: Option<String>,
+    version: Option<u8>,
+    posted_from: Option<u64>,
+    posted_to: Option<u64>,
 ) -> ResponseMessage {
     let mut feathers = vec![];
     let key = FeatherDbKey {
         block_number: block_number.into(),
         index: 0.into(),
-        account_id: [0; 32].into(),
+        sub_index: 0.into(),
     };
     debug!("search key: {:?}", key.as_bytes());
-    let mut iter = feathers_db.range(key.as_bytes()..);
+    let mut iter = trees.feather.range(key.as_bytes()..);
 
     while let Some(Ok((key, value))) = iter.next_back() {
         debug!("key: {:?}", key);
-        if let Ok(key) = FeatherDbKey::read_from_bytes(&key) {
-            if let Some(account_id) = account_id.clone() {
-                if key.account_id != account_id.0 {
-                    continue;
-                }
+        if let Ok((key, author)) = FeatherDbKey::read_from_prefix(&key) {
+            if let Some(account_id) = &account_id
+                && author != account_id.0
+            {
+                continue;
             }
 
-            let remark: String = value.to_vec().try_into().unwrap();
+            let posted_at = find_posted_at(trees, &key);
+
+            // Feathers are returned newest first, so none of the remaining feathers can be in range.
+            if let Some(posted_from) = posted_from
+                && posted_at.is_some_and(|posted_at| posted_at < posted_from)
+            {
+                break;
+            }
+            if (posted_from.is_some() || posted_to.is_some()) && posted_at.is_none() {
+                continue;
+            }
+            if let Some(posted_to) = posted_to
+                && posted_at.is_some_and(|posted_at| posted_at > posted_to)
+            {
+                continue;
+            }
 
-            if let Some(genre) = genre.clone() {
-                let components: Vec<&str> = remark.split("::").collect();
-                if components[1].to_string() != genre {
-                    continue;
-                }
+            let Some((feather, latest)) = load_feather(trees, &key, author, &value) else {
+                continue;
+            };
+            if version.is_some_and(|version| version != feather.version) {
+                continue;
+            }
+            if let Some(genre) = &genre
+                && latest
+                    .as_ref()
+                    .and_then(|latest| latest.post())
+                    .is_none_or(|post| post.genre != *genre)
+            {
+                continue;
             }
-            feathers.push(Feather {
-                block_number: key.block_number.into(),
-                index: key.index.into(),
-                account_id: subxt::utils::AccountId32(key.account_id),
-                remark: remark,
-            });
+            feathers.push(feather);
 
             let len: u32 = feathers.len().try_into().unwrap();
 
@@ -76,27 +107,437 @@ pub fn process_msg_get_feathers(
     ResponseMessage::Feathers(feathers)
 }
 
-pub async fn process_msg(
+pub fn process_msg_get_revisions(
+    trees: &Trees,
+    block_number: u32,
+    index: u16,
+    sub_index: u16,
+) -> ResponseMessage {
+    let key = FeatherDbKey {
+        block_number: block_number.into(),
+        index: index.into(),
+        sub_index: sub_index.into(),
+    };
+    let Some(Ok((feather_key, value))) = trees.feather.scan_prefix(key.as_bytes()).next() else {
+        return ResponseMessage::Error("unknown feather".into());
+    };
+    let author = &feather_key[key.as_bytes().len()..];
+    let mut revisions: Vec<Revision> = find_revisions(trees, &key, author)
+        .iter()
+        .filter_map(|(key, value)| revision(trees, key, value))
+        .collect();
+    if let Some(feather) = revision(trees, &key, &value) {
+        revisions.insert(0, feather);
+    }
+    ResponseMessage::Revisions(revisions)
+}
+
+pub fn process_msg_get_thread(
+    trees: &Trees,
+    block_number: u32,
+    index: u16,
+    offset: Option<u32>,
+    limit: u32,
+) -> ResponseMessage {
+    let extrinsic_key = ExtrinsicDbKey {
+        block_number: block_number.into(),
+        index: index.into(),
+    };
+    let Some(Ok((key, value))) = trees.feather.scan_prefix(extrinsic_key.as_bytes()).next() else {
+        return ResponseMessage::Error("unknown feather".into());
+    };
+    let Ok((key, author)) = FeatherDbKey::read_from_prefix(&key) else {
+        return ResponseMessage::Error("unknown feather".into());
+    };
+    match load_thread(trees, &key, author, &value, offset.unwrap_or(0), limit) {
+        Some(thread) => ResponseMessage::Thread(thread),
+        None => ResponseMessage::Error("unknown feather".into()),
+    }
+}
+
+/// Load a feather with its replies, skipping the first `offset` replies and returning at most `limit` of them.
+fn load_thread(
+    trees: &Trees,
+    key: &FeatherDbKey,
+    author: &[u8],
+    value: &[u8],
+    offset: u32,
+    limit: u32,
+) -> Option<Thread> {
+    let (feather, _) = load_feather(trees, key, author, value)?;
+    let replies = find_replies(trees, key);
+    let reply_count = replies.len().try_into().unwrap();
+    // Only the first level of replies is loaded. Their replies are counted, and can be queried as threads of their own.
+    let replies = replies
+        .into_iter()
+        .skip(offset.try_into().unwrap())
+        .take(limit.try_into().unwrap())
+        .filter_map(|(reply, author)| {
+            let mut reply_key = reply.as_bytes().to_vec();
+            reply_key.extend_from_slice(&author);
+            let value = trees.feather.get(reply_key).ok().flatten()?;
+            let (feather, _) = load_feather(trees, &reply, &author, &value)?;
+            Some(Thread {
+                feather,
+                reply_count: find_replies(trees, &reply).len().try_into().unwrap(),
+                replies: vec![],
+            })
+        })
+        .collect();
+    Some(Thread {
+        feather,
+        reply_count,
+        replies,
+    })
+}
+
+/// Find the keys and authors of the replies to a feather, oldest first. Replies are to the first feather in an extrinsic.
+fn find_replies(trees: &Trees, key: &FeatherDbKey) -> Vec<(FeatherDbKey, Vec<u8>)> {
+    let extrinsic_key = ExtrinsicDbKey {
+        block_number: key.block_number,
+        index: key.index,
+    };
+    let first = trees
+        .feather
+        .scan_prefix(extrinsic_key.as_bytes())
+        .flatten()
+        .find_map(|(key, _)| {
+            FeatherDbKey::read_from_prefix(&key)
+                .ok()
+                .map(|(key, _)| key)
+        });
+    if first.as_ref() != Some(key) {
+        return vec![];
+    }
+    trees
+        .reply
+        .scan_prefix(extrinsic_key.as_bytes())
+        .flatten()
+        .filter_map(|(reply_key, _)| {
+            ReplyDbKey::read_from_prefix(&reply_key)
+                .ok()
+                .map(|(reply_key, author)| (reply_key.reply, author.to_vec()))
+        })
+        .collect()
+}
+
+/// Load the latest revision of a feather, and parse its remark.
+fn load_feather(
+    trees: &Trees,
+    key: &FeatherDbKey,
+    author: &[u8],
+    value: &[u8],
+) -> Option<(Feather, Option<FeatherRemark>)> {
+    let reply_to = FeatherDbValue::decode(value)
+        .and_then(|(_, _, remark)| FeatherRemark::parse(remark).ok().flatten())
+        .and_then(|feather| match feather.op {
+            FeatherOp::Reply(parent, _) => Some(Parent {
+                block_number: parent.block_number,
+                index: parent.index,
+            }),
+            _ => None,
+        });
+    let (_, version, _) = FeatherDbValue::decode(value)?;
+    let revisions = find_revisions(trees, key, author);
+    let latest = revisions.last().map_or(value, |(_, value)| value);
+    let (encoding, latest_version, remark_bytes) = FeatherDbValue::decode(latest)?;
+    let latest = FeatherRemark::parse(remark_bytes).ok().flatten();
+    let (extrinsic_hash, signer) = find_extrinsic(trees, key);
+    let feather = Feather {
+        chain: trees.chain.clone(),
+        block_number: key.block_number.into(),
+        block_hash: find_block_hash(trees, key),
+        index: key.index.into(),
+        sub_index: key.sub_index.into(),
+        extrinsic_hash,
+        account_id: AccountId(author.to_vec()),
+        signer: AccountId(signer.unwrap_or(author.to_vec())),
+        posted_at: find_posted_at(trees, key),
+        version,
+        revision_version: (!revisions.is_empty()).then_some(latest_version),
+        encoding,
+        remark: remark_string(encoding, remark_bytes),
+        revisions: revisions.len().try_into().unwrap(),
+        deleted: latest
+            .as_ref()
+            .is_some_and(|latest| matches!(latest.op, FeatherOp::Delete(_))),
+        reply_to,
+    };
+    Some((feather, latest))
+}
+
+pub fn process_msg_get_partial_feathers(
+    trees: &Trees,
+    part_window: u32,
+    account_id: Option<AccountId>,
+    limit: u32,
+) -> ResponseMessage {
+    let head = trees
+        .span
+        .last()
+        .ok()
+        .flatten()
+        .and_then(|(key, _)| <[u8; 4]>::try_from(key.as_ref()).ok())
+        .map_or(0, u32::from_be_bytes);
+    let prefix = match &account_id {
+        Some(account_id) => {
+            let mut prefix = vec![account_id.0.len().try_into().unwrap_or(0)];
+            prefix.extend_from_slice(&account_id.0);
+            prefix
+        }
+        None => vec![],
+    };
+    // Group the parts by set.
+    let mut sets: Vec<(Vec<u8>, Vec<u8>, Vec<_>)> = vec![];
+    for (set_key, _) in trees.part_set.scan_prefix(prefix).flatten() {
+        let Some((author, id, part)) = PartSetDbKey::decode(&set_key) else {
+            continue;
+        };
+        let Some(value) = trees
+            .part
+            .get([part.position.as_bytes(), author].concat())
+            .ok()
+            .flatten()
+        else {
+            continue;
+        };
+        match sets.last_mut() {
+            Some((last_author, last_id, parts)) if *last_author == author && *last_id == id => {
+                parts.push((part, value.to_vec()))
+            }
+            _ => sets.push((author.to_vec(), id.to_vec(), vec![(part, value.to_vec())])),
+        }
+    }
+    let mut partial_feathers = vec![];
+    for (author, id, parts) in sets {
+        // Sets that have formed a feather are not partial, even if parts were posted again after.
+        if !PartSet::complete(&parts, part_window).is_empty() {
+            continue;
+        }
+        let set = PartSet::latest(&parts, part_window);
+        partial_feathers.push(PartialFeather {
+            account_id: AccountId(author),
+            id: String::from_utf8_lossy(&id).into_owned(),
+            count: set.count,
+            parts: set.parts.iter().map(|(part, _, _)| *part).collect(),
+            first_block: set.first_block(),
+            last_block: set.last_block(),
+            expired: head.saturating_sub(set.first_block()) > part_window,
+        });
+        if partial_feathers.len() == usize::try_from(limit).unwrap() {
+            break;
+        }
+    }
+    ResponseMessage::PartialFeathers(partial_feathers)
+}
+
+/// Find the edits and deletions of a feather by its author, oldest first. Only the first feather an author posted in an extrinsic can be revised.
+fn find_revisions(
     trees: &Trees,
+    key: &FeatherDbKey,
+    author: &[u8],
+) -> Vec<(FeatherDbKey, Vec<u8>)> {
+    let extrinsic_key = ExtrinsicDbKey {
+        block_number: key.block_number,
+        index: key.index,
+    };
+    let first = trees
+        .feather
+        .scan_prefix(extrinsic_key.as_bytes())
+        .flatten()
+        .filter_map(|(key, _)| {
+            FeatherDbKey::read_from_prefix(&key)
+                .ok()
+                .filter(|(_, key_author)| *key_author == author)
+                .map(|(key, _)| key)
+        })
+        .next();
+    if first.as_ref() != Some(key) {
+        return vec![];
+    }
+    let mut revisions = vec![];
+    for (index_key, _) in trees
+        .revision_index
+        .scan_prefix(extrinsic_key.as_bytes())
+        .flatten()
+    {
+        let Ok((index_key, revision_author)) = RevisionIndexDbKey::read_from_prefix(&index_key)
+        else {
+            continue;
+        };
+        // Revisions by anyone else are ignored.
+        if revision_author != author {
+            continue;
+        }
+        let mut revision_key = index_key.revision.as_bytes().to_vec();
+        revision_key.extend_from_slice(author);
+        if let Some(value) = trees.revision.get(revision_key).ok().flatten()
+            && let Ok((_, value)) = RevisionDbValue::read_from_prefix(&value)
+        {
+            revisions.push((index_key.revision, value.to_vec()));
+        }
+    }
+    revisions
+}
+
+/// A feather or revision as it was posted.
+fn revision(trees: &Trees, key: &FeatherDbKey, value: &[u8]) -> Option<Revision> {
+    let (encoding, version, remark_bytes) = FeatherDbValue::decode(value)?;
+    let deleted = matches!(
+        FeatherRemark::parse(remark_bytes),
+        Ok(Some(FeatherRemark {
+            op: FeatherOp::Delete(_),
+            ..
+        }))
+    );
+    Some(Revision {
+        block_number: key.block_number.into(),
+        block_hash: find_block_hash(trees, key),
+        index: key.index.into(),
+        sub_index: key.sub_index.into(),
+        extrinsic_hash: find_extrinsic(trees, key).0,
+        posted_at: find_posted_at(trees, key),
+        version,
+        encoding,
+        remark: remark_string(encoding, remark_bytes),
+        deleted,
+    })
+}
+
+/// Find when the block of a feather was authored.
+fn find_posted_at(trees: &Trees, key: &FeatherDbKey) -> Option<u64> {
+    trees
+        .timestamp
+        .get(key.block_number.as_bytes())
+        .ok()
+        .flatten()
+        .and_then(|timestamp| <[u8; 8]>::try_from(timestamp.as_ref()).ok())
+        .map(u64::from_be_bytes)
+}
+
+/// Find the hash of the block of a feather.
+fn find_block_hash(trees: &Trees, key: &FeatherDbKey) -> Option<H256> {
+    trees
+        .block_hash
+        .get(key.block_number.as_bytes())
+        .ok()
+        .flatten()
+        .and_then(|hash| <[u8; 32]>::try_from(hash.as_ref()).ok())
+        .map(H256)
+}
+
+/// Find the hash and signer of the extrinsic of a feather. Extrinsics indexed before the signer was stored were signed by the author.
+fn find_extrinsic(trees: &Trees, key: &FeatherDbKey) -> (Option<H256>, Option<Vec<u8>>) {
+    let extrinsic_key = ExtrinsicDbKey {
+        block_number: key.block_number,
+        index: key.index,
+    };
+    let extrinsic = trees.extrinsic.get(extrinsic_key.as_bytes()).ok().flatten();
+    match extrinsic
+        .as_ref()
+        .and_then(|value| ExtrinsicDbValue::read_from_prefix(value).ok())
+    {
+        Some((value, signer)) if !signer.is_empty() => {
+            (Some(H256(value.hash)), Some(signer.to_vec()))
+        }
+        Some((value, _)) => (Some(H256(value.hash)), None),
+        None => (None, None),
+    }
+}
+
+/// Format a stored remark for a response.
+fn remark_string(encoding: RemarkEncoding, remark: &[u8]) -> String {
+    match encoding {
+        RemarkEncoding::Utf8 => String::from_utf8_lossy(remark).into_owned(),
+        RemarkEncoding::Binary => format!("0x{}", hex::encode(remark)),
+    }
+}
+
+/// Find the chain a request is for. Requests without a chain are for the first chain.
+fn find_chain<'a>(
+    chains: &'a [IndexedChain],
+    chain: &Option<String>,
+) -> Result<&'a IndexedChain, ResponseMessage> {
+    match chain {
+        Some(name) => chains
+            .iter()
+            .find(|chain| chain.trees.chain == *name)
+            .ok_or_else(|| ResponseMessage::Error(format!("unknown chain: {}", name))),
+        None => Ok(&chains[0]),
+    }
+}
+
+pub async fn process_msg(
+    chains: &[IndexedChain],
     msg: RequestMessage,
 ) -> Result<ResponseMessage, IndexError> {
     debug!("{:?}", msg);
     Ok(match msg {
-        RequestMessage::Status => process_msg_status(&trees.span),
+        RequestMessage::Status { chain } => match find_chain(chains, &chain) {
+            Ok(chain) => process_msg_status(&chain.trees, &chain.status_rx.borrow()),
+            Err(response) => response,
+        },
         RequestMessage::GetFeathers {
+            chain,
             block_number,
             limit,
             account_id,
             genre,
-        } => process_msg_get_feathers(&trees.feather, block_number, limit, account_id, genre),
-        RequestMessage::SizeOnDisk => ResponseMessage::SizeOnDisk(trees.root.size_on_disk()?),
+            version,
+            posted_from,
+            posted_to,
+        } => match find_chain(chains, &chain) {
+            Ok(chain) => process_msg_get_feathers(
+                &chain.trees,
+                block_number,
+                limit,
+                account_id,
+                genre,
+                version,
+                posted_from,
+                posted_to,
+            ),
+            Err(response) => response,
+        },
+        RequestMessage::GetRevisions {
+            chain,
+            block_number,
+            index,
+            sub_index,
+        } => match find_chain(chains, &chain) {
+            Ok(chain) => process_msg_get_revisions(&chain.trees, block_number, index, sub_index),
+            Err(response) => response,
+        },
+        RequestMessage::GetThread {
+            chain,
+            block_number,
+            index,
+            offset,
+            limit,
+        } => match find_chain(chains, &chain) {
+            Ok(chain) => process_msg_get_thread(&chain.trees, block_number, index, offset, limit),
+            Err(response) => response,
+        },
+        RequestMessage::GetPartialFeathers {
+            chain,
+            account_id,
+            limit,
+        } => match find_chain(chains, &chain) {
+            Ok(chain) => {
+                process_msg_get_partial_feathers(&chain.trees, chain.part_window, account_id, limit)
+            }
+            Err(response) => response,
+        },
+        RequestMessage::SizeOnDisk => {
+            ResponseMessage::SizeOnDisk(chains[0].trees.root.size_on_disk()?)
+        }
     })
 }
 
 async fn handle_connection(
     raw_stream: TcpStream,
     addr: SocketAddr,
-    trees: Trees,
+    chains: Vec<IndexedChain>,
 ) -> Result<(), IndexError> {
     info!("Incoming TCP connection from: {}", addr);
     let ws_stream = tokio_tungstenite::accept_async(raw_stream).await?;
@@ -112,7 +553,7 @@ async fn handle_connection(
                   if msg.is_text() || msg.is_binary() {
                       match serde_json::from_str(msg.to_text()?) {
                           Ok(request_json) => {
-                              let response_msg = process_msg(&trees, request_json).await?;
+                              let response_msg = process_msg(&chains, request_json).await?;
                               let response_json = serde_json::to_string(&response_msg).unwrap();
                               ws_sender.send(tungstenite::Message::Text(response_json.into())).await?;
                           },
@@ -124,7 +565,7 @@ async fn handle_connection(
     }
 }
 
-pub async fn websockets_listen(trees: Trees, port: u16, mut exit_rx: Receiver<bool>) {
+pub async fn websockets_listen(chains: Vec<IndexedChain>, port: u16, mut exit_rx: Receiver<bool>) {
     let mut addr = "0.0.0.0:".to_string();
     addr.push_str(&port.to_string());
 
@@ -145,7 +586,7 @@ pub async fn websockets_listen(trees: Trees, port: u16, mut exit_rx: Receiver<bo
                 tokio::spawn(handle_connection(
                     stream,
                     addr,
-                    trees.clone(),
+                    chains.clone(),
                 ));
             }
         }